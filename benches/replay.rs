@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use async_std::task;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use surf::{http::Method, StatusCode, Url};
+use surf_vcr::{
+    Body,
+    MemoryCassette,
+    VcrCassette,
+    VcrMiddleware,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+const CASSETTE: &str = "test-sessions/simple.yml";
+
+async fn replay(vcr: VcrMiddleware) {
+    let client = surf::Client::new().with(vcr);
+
+    let req = surf::get("https://example.com")
+        .header("X-some-header", "another hello")
+        .build();
+
+    client.send(req).await.unwrap();
+}
+
+fn load_for_each_middleware(c: &mut Criterion) {
+    c.bench_function("load cassette for each middleware", |b| {
+        b.iter(|| task::block_on(async {
+            let cassette = VcrCassette::load(CASSETTE).await.unwrap();
+            replay(VcrMiddleware::from_memory(cassette.into_memory())).await;
+        }))
+    });
+}
+
+fn share_memory_cassette(c: &mut Criterion) {
+    let cassette: MemoryCassette = task::block_on(VcrCassette::load(CASSETTE))
+        .unwrap()
+        .into_memory();
+
+    c.bench_function("share cassette in memory", |b| {
+        b.iter(|| task::block_on(
+            replay(VcrMiddleware::from_memory(cassette.clone()))
+        ))
+    });
+}
+
+// A cassette with an interaction for each of `len` URLs.
+fn large_cassette(len: usize) -> MemoryCassette {
+    VcrCassette::build_from_pairs((0..len).map(|i| (
+        VcrRequest {
+            method: Method::Get,
+            url: Url::parse(&format!("https://example.com/items/{}", i))
+                .unwrap(),
+            headers: HashMap::new(),
+            body: Body::Str("".to_owned()),
+        },
+        VcrResponse {
+            status: StatusCode::Ok,
+            version: None,
+            headers: HashMap::new(),
+            body: Body::Str(format!("item {}", i)),
+            transformed: None,
+            last_played_at: None,
+            title: None,
+            tracing_id: None,
+            timing: None,
+        }
+    ))).into_memory()
+}
+
+fn replay_from_large_cassette(c: &mut Criterion) {
+    const LEN: usize = 500;
+    let cassette = large_cassette(LEN);
+
+    c.bench_function("replay every request of a large cassette", |b| {
+        b.iter(|| task::block_on(async {
+            let client = surf::Client::new()
+                .with(VcrMiddleware::from_memory(cassette.clone()));
+
+            for i in 0..LEN {
+                let url = format!("https://example.com/items/{}", i);
+                client.get(url).await.unwrap();
+            }
+        }))
+    });
+}
+
+criterion_group!(
+    benches,
+    load_for_each_middleware,
+    share_memory_cassette,
+    replay_from_large_cassette
+);
+criterion_main!(benches);
@@ -17,7 +17,6 @@ use std::env;
 
 use async_std::task;
 
-use surf;
 use surf_vcr::{VcrMiddleware, VcrMode};
 
 fn main() {
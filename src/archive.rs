@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use async_std::fs;
+use flate2::read::GzDecoder;
+
+use crate::{VcrCassette, VcrError, VcrMiddleware, VcrMode};
+
+
+impl VcrCassette {
+    /// Read a cassette from an entry of a gzip-compressed tar archive
+    /// (`.tar.gz`), so a suite of cassettes can be distributed as a single
+    /// file.
+    ///
+    /// `entry_name` is the path of the cassette within the archive, such as
+    /// `cassettes/login.yml`; a leading `./` in the archive's entry names is
+    /// ignored. Entries whose names end in `.json`, or whose contents start
+    /// with `[`, are read as JSON cassettes, and all others as YAML. Response
+    /// bodies stored in separate files are not read from the archive.
+    ///
+    /// Archives require the `archives` feature.
+    pub async fn load_from_archive<P, E>(archive_path: P, entry_name: E)
+    -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+              E: AsRef<Path>,
+    {
+        let archive_path = archive_path.as_ref();
+        let entry_name = entry_name.as_ref();
+
+        let bytes = fs::read(archive_path).await?;
+
+        let text = read_entry(&bytes, entry_name)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!(
+                "{} is not in {}",
+                entry_name.display(),
+                archive_path.display()
+            ))
+        })?;
+
+        let is_json = entry_name.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        if is_json || text.trim_start().starts_with('[') {
+            Self::from_json(&text)
+        } else {
+            Self::from_yaml(&text)
+        }
+    }
+}
+
+impl VcrMiddleware {
+    /// Create a middleware that replays a cassette read from an entry of a
+    /// gzip-compressed tar archive; see [VcrCassette::load_from_archive].
+    ///
+    /// Archives are read-only: modes that record to the cassette fail with
+    /// [VcrError::ReadOnlyArchive], and `Once` and `None` replay the
+    /// cassette.
+    ///
+    /// ```no_run
+    /// # async fn create() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::{VcrMiddleware, VcrMode};
+    ///
+    /// let vcr = VcrMiddleware::from_archive(
+    ///     "fixtures/sessions.tar.gz",
+    ///     "sessions/login.yml",
+    ///     VcrMode::Replay
+    /// ).await?;
+    /// # Ok(()) }
+    /// ```
+    pub async fn from_archive<P, E>(
+        archive_path: P,
+        entry_name: E,
+        mode: VcrMode
+    ) -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+              E: AsRef<Path>,
+    {
+        let archive_path = archive_path.as_ref();
+
+        match mode.resolve(true) {
+            VcrMode::Replay => {},
+            VcrMode::Passthrough => return Ok(
+                Self::with_options(VcrMode::Passthrough, PathBuf::new())
+            ),
+            _ => return Err(VcrError::ReadOnlyArchive(archive_path.to_owned())),
+        }
+
+        let cassette = VcrCassette::load_from_archive(archive_path, entry_name)
+            .await?;
+
+        Ok(Self::from_memory(cassette.into_memory()))
+    }
+}
+
+// Read the text of the named entry of a gzip-compressed tar archive.
+fn read_entry(archive: &[u8], name: &Path) -> io::Result<Option<String>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if path.strip_prefix(".").unwrap_or(&path) == name {
+            let mut text = String::new();
+            entry.read_to_string(&mut text)?;
+            return Ok(Some(text));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use crate::tests::Scratch;
+
+    // Write an archive containing the simple cassette.
+    fn write_archive(path: &str) -> io::Result<()> {
+        let mut builder = tar::Builder::new(
+            GzEncoder::new(Vec::new(), Compression::default())
+        );
+
+        builder.append_path_with_name(
+            "test-sessions/simple.yml",
+            "./sessions/simple.yml"
+        )?;
+        builder.append_path_with_name(
+            "test-sessions/simple.json",
+            "sessions/simple.json"
+        )?;
+
+        std::fs::write(path, builder.into_inner()?.finish()?)
+    }
+
+    #[async_std::test]
+    async fn load_cassettes_from_archive() -> Result<(), VcrError> {
+        let path = "test-sessions/scratch/archive-test.tar.gz";
+        let _path = Scratch::new(path);
+
+        write_archive(path)?;
+
+        let yaml = VcrCassette::load_from_archive(path, "sessions/simple.yml")
+            .await?;
+        let json = VcrCassette::load_from_archive(path, "sessions/simple.json")
+            .await?;
+
+        assert_eq!(yaml, VcrCassette::load("test-sessions/simple.yml").await?);
+        assert_eq!(json.requests, yaml.requests);
+
+        match VcrCassette::load_from_archive(path, "simple.yml").await {
+            Err(VcrError::File(e)) =>
+                assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            res => panic!("Expected a missing entry; got {:?}", res),
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn replay_from_archive() -> Result<(), VcrError> {
+        let path = "test-sessions/scratch/replay-archive-test.tar.gz";
+        let _path = Scratch::new(path);
+
+        write_archive(path)?;
+
+        let vcr = VcrMiddleware::from_archive(
+            path,
+            "sessions/simple.yml",
+            VcrMode::Once
+        ).await?;
+
+        let mut res = surf::Client::new().with(vcr)
+            .get("https://example.com")
+            .header("X-some-header", "hello")
+            .body_string("My Request".to_owned())
+            .await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "A Response");
+
+        for mode in [VcrMode::Record, VcrMode::NewEpisodes, VcrMode::All].iter()
+        {
+            assert!(matches!(
+                VcrMiddleware::from_archive(path, "sessions/simple.yml", *mode)
+                    .await,
+                Err(VcrError::ReadOnlyArchive(_))
+            ));
+        }
+
+        Ok(())
+    }
+}
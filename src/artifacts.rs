@@ -0,0 +1,288 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    core::Session,
+    matching,
+    preview,
+    VcrCassette,
+    VcrMatchOptions,
+    VcrRequest,
+};
+
+
+// The number of closest recorded requests described in a replay miss.
+const CLOSEST_CANDIDATES: usize = 3;
+
+/// A description of a replay failure, written as JSON to the directory set by
+/// `VcrMiddleware::with_failure_artifacts`; see [collect_failure_artifacts].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FailureArtifact {
+    /// The name of the test that failed, taken from its thread's name, or
+    /// `vcr` if the thread is unnamed.
+    pub test: String,
+    pub cassette: PathBuf,
+    pub kind: FailureKind,
+    /// The request that matched no recorded request, for a replay miss.
+    pub request: Option<RequestSummary>,
+    /// The recorded requests differing from `request` in the fewest fields.
+    pub closest: Vec<CandidateDiff>,
+    /// The interactions that were not replayed, for a strict session.
+    pub unplayed: Vec<String>,
+    pub stats: FailureStats,
+}
+
+/// The kinds of replay failure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// A request matched no recorded request.
+    ReplayMiss,
+    /// A strict session finished without replaying every interaction (see
+    /// `VcrMiddleware::strict`).
+    UnplayedInteractions,
+}
+
+/// A request as described in a [FailureArtifact]; the body is shortened as
+/// for other diagnostics (see [set_diagnostic_body_limit]).
+///
+/// [set_diagnostic_body_limit]: crate::set_diagnostic_body_limit
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RequestSummary {
+    pub method: String,
+    pub url: String,
+    pub headers: HashMap<String, Vec<String>>,
+    pub body: String,
+}
+
+/// The differences between a request and a recorded request.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CandidateDiff {
+    /// The position of the recorded request in the cassette.
+    pub index: usize,
+    /// Each field that differs, such as
+    /// `body differs: recorded "a", requested "b"`.
+    pub differences: Vec<String>,
+}
+
+/// The state of the session when it failed.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FailureStats {
+    /// The number of interactions in the cassette.
+    pub interactions: usize,
+    /// The number of requests the middleware accepted.
+    pub requests: usize,
+    /// The number of interactions that were replayed.
+    pub replayed: usize,
+}
+
+/// Read the failure artifacts in `dir`, in the order of their file names.
+///
+/// A missing directory has no artifacts; files that can't be read as
+/// artifacts are skipped with a warning.
+///
+/// ```no_run
+/// let failures = surf_vcr::collect_failure_artifacts("target/vcr-failures");
+///
+/// for failure in failures.iter() {
+///     println!("{}: {:?} in {}",
+///         failure.test, failure.kind, failure.cassette.display());
+/// }
+/// ```
+pub fn collect_failure_artifacts<P>(dir: P) -> Vec<FailureArtifact>
+    where P: AsRef<Path>,
+{
+    let mut paths = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>(),
+        Err(_) => return vec![],
+    };
+
+    paths.sort();
+
+    paths.iter()
+        .filter_map(|path| {
+            let artifact = std::fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_slice(&json)
+                    .map_err(|e| e.to_string()));
+
+            match artifact {
+                Ok(artifact) => Some(artifact),
+                Err(e) => {
+                    log::warn!(
+                        "Skipping failure artifact {}: {}",
+                        path.display(),
+                        e
+                    );
+                    None
+                },
+            }
+        })
+        .collect()
+}
+
+impl Session {
+    // Describe a request that matched nothing in the cassette.
+    pub(crate) async fn write_replay_miss(
+        &self,
+        cassette: &VcrCassette,
+        request: &VcrRequest,
+        opts: &VcrMatchOptions,
+        body_limit: usize
+    ) {
+        let mut closest = cassette.requests.iter()
+            .enumerate()
+            .map(|(index, rec)| {
+                let differences =
+                    matching::differences(rec, request, opts, body_limit);
+
+                CandidateDiff {
+                    index,
+                    differences: differences.iter()
+                        .map(|d| d.to_string())
+                        .collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        closest.sort_by_key(|c| (c.differences.len(), c.index));
+        closest.truncate(CLOSEST_CANDIDATES);
+
+        let request = RequestSummary {
+            method: request.method.to_string(),
+            url: request.url.to_string(),
+            headers: request.headers.clone(),
+            body: preview::fmt_body_preview(&request.body, body_limit),
+        };
+
+        self.write_failure(
+            FailureKind::ReplayMiss,
+            Some(request),
+            closest,
+            vec![],
+            cassette.requests.len()
+        ).await;
+    }
+
+    // Describe the interactions a strict session didn't replay.
+    pub(crate) async fn write_unplayed(&self, unplayed: Vec<String>) {
+        let interactions = self.interactions().await.len();
+
+        let unplayed = unplayed.iter()
+            .map(|line| line.trim().to_owned())
+            .collect();
+
+        self.write_failure(
+            FailureKind::UnplayedInteractions,
+            None,
+            vec![],
+            unplayed,
+            interactions
+        ).await;
+    }
+
+    async fn write_failure(
+        &self,
+        kind: FailureKind,
+        request: Option<RequestSummary>,
+        closest: Vec<CandidateDiff>,
+        unplayed: Vec<String>,
+        interactions: usize
+    ) {
+        let dir = match &self.failure_artifact_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        let state = &self.state.0;
+
+        let test = std::thread::current().name()
+            .unwrap_or("vcr")
+            .to_owned();
+
+        let stem = self.file.file_stem()
+            .map_or("memory".into(), |s| s.to_string_lossy());
+
+        let n = state.failure_artifacts.fetch_add(1, Ordering::SeqCst);
+        let name = file_name(&format!("{}-{}-{}", test, stem, n));
+
+        let artifact = FailureArtifact {
+            test,
+            cassette: self.file.clone(),
+            kind,
+            request,
+            closest,
+            unplayed,
+            stats: FailureStats {
+                interactions,
+                requests: state.handled.load(Ordering::SeqCst),
+                replayed: state.played.lock().unwrap().len(),
+            },
+        };
+
+        let path = dir.join(name);
+
+        // The failure is reported by the caller; a missing artifact is only
+        // worth a warning.
+        let written = async {
+            async_std::fs::create_dir_all(dir).await?;
+
+            let json = serde_json::to_vec_pretty(&artifact)
+                .map_err(std::io::Error::from)?;
+
+            async_std::fs::write(&path, json).await
+        }.await;
+
+        if let Err(e) = written {
+            log::warn!(
+                "Failed to write failure artifact {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Make `name` safe to use as a file name, such as `tests__login-login-0.json`
+// for a test named `tests::login`.
+fn file_name(name: &str) -> String {
+    let name = name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    format!("{}.json", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_artifacts_for_their_test() {
+        assert_eq!(
+            file_name("tests::login-login-0"),
+            "tests__login-login-0.json"
+        );
+        assert_eq!(file_name("a b/c-d-1"), "a_b_c-d-1.json");
+    }
+
+    #[test]
+    fn collect_from_missing_directory() {
+        assert!(collect_failure_artifacts("test-sessions/missing").is_empty());
+    }
+}
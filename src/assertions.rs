@@ -0,0 +1,271 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+use http_types::{Method, StatusCode};
+
+use crate::{
+    preview::{diagnostic_body_limit, fmt_text_preview},
+    Body,
+    VcrCassette,
+};
+
+
+impl VcrCassette {
+    /// Panic if the body of any request or response does not match the format
+    /// its `content-type` header declares.
+    ///
+    /// JSON content types (`application/json` and `*+json`) require a valid
+    /// JSON body; `application/x-www-form-urlencoded` requires a body that is
+    /// not JSON and contains no unencoded whitespace; and `text/*` and JSON
+    /// content types require a text body. Empty bodies and bodies stored in
+    /// separate files are not checked.
+    ///
+    /// The panic message lists every inconsistent body.
+    pub fn assert_content_types_consistent(&self) {
+        let mut problems = vec![];
+
+        for (i, (req, res)) in
+            self.requests.iter().zip(self.responses.iter()).enumerate()
+        {
+            let bodies = [
+                ("request", &req.headers, &req.body),
+                ("response", &res.headers, &res.body),
+            ];
+
+            for (side, headers, body) in bodies.iter() {
+                if let Some(problem) = content_type_problem(headers, body) {
+                    problems.push(format!("  #{} {}: {}", i, side, problem));
+                }
+            }
+        }
+
+        if ! problems.is_empty() {
+            panic!(
+                "Bodies do not match their content types:\n{}",
+                problems.join("\n")
+            );
+        }
+    }
+
+    /// Panic if any interaction has an empty request or response body where a
+    /// body is expected, which usually means the recording failed.
+    ///
+    /// Request bodies are checked for `POST`, `PUT`, and `PATCH` requests.
+    /// Response bodies are checked unless the request is a `HEAD` request or
+    /// the status is informational, `204 No Content`, or `304 Not Modified`.
+    /// Bodies stored in separate files are not checked.
+    ///
+    /// The panic message lists the index, method, URL, and status of every
+    /// interaction with an empty body.
+    pub fn assert_no_empty_bodies(&self) {
+        let mut problems = vec![];
+
+        for (i, (req, res)) in
+            self.requests.iter().zip(self.responses.iter()).enumerate()
+        {
+            let request_expected = matches!(
+                req.method,
+                Method::Post | Method::Put | Method::Patch
+            );
+
+            let response_expected = req.method != Method::Head
+                && ! res.status.is_informational()
+                && res.status != StatusCode::NoContent
+                && res.status != StatusCode::NotModified;
+
+            let sides = [
+                ("request", request_expected, &req.body),
+                ("response", response_expected, &res.body),
+            ];
+
+            for (side, expected, body) in sides.iter() {
+                if *expected && body.known_len() == Some(0) {
+                    problems.push(format!("  #{} {} {} ({}): empty {} body",
+                        i, req.method, req.url, res.status, side));
+                }
+            }
+        }
+
+        if ! problems.is_empty() {
+            panic!("Bodies are empty:\n{}", problems.join("\n"));
+        }
+    }
+
+    /// Panic if no request in the cassette uses any of the given methods.
+    ///
+    /// This can check that write operations were recorded:
+    ///
+    /// ```
+    /// # async fn check() -> Result<(), surf_vcr::VcrError> {
+    /// use surf::http::Method;
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+    /// cassette.assert_at_least_one_of(&[Method::Get, Method::Head]);
+    /// # Ok(()) }
+    /// ```
+    pub fn assert_at_least_one_of(&self, methods: &[Method]) {
+        if ! self.requests.iter().any(|req| methods.contains(&req.method)) {
+            let methods = methods.iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>();
+
+            panic!(
+                "No request in the cassette uses any of: {}",
+                methods.join(", ")
+            );
+        }
+    }
+}
+
+fn content_type_problem(headers: &HashMap<String, Vec<String>>, body: &Body)
+-> Option<String> {
+    let content_type = headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, v)| v.first())?;
+
+    let media_type = content_type.split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    let is_json = media_type == "application/json"
+        || media_type.ends_with("+json");
+
+    let text = match body {
+        Body::Str(s) if s.is_empty() => return None,
+        Body::Str(s) => s,
+        Body::Bytes(b) if b.is_empty() => return None,
+        Body::Bytes(_) if is_json || media_type.starts_with("text/") =>
+            return Some(format!(
+                "{} declared, but the body is binary",
+                content_type
+            )),
+        Body::Bytes(_) | Body::File { .. } | Body::Ref { .. } => return None,
+    };
+
+    let parses_as_json = serde_json::from_str::<Value>(text).is_ok();
+    let preview = fmt_text_preview(text, diagnostic_body_limit());
+
+    if is_json && ! parses_as_json {
+        Some(format!("{} declared, but the body is not JSON: {}",
+            content_type, preview))
+    } else if media_type == "application/x-www-form-urlencoded" {
+        if parses_as_json {
+            Some(format!("{} declared, but the body is JSON: {}",
+                content_type, preview))
+        } else if text.chars().any(char::is_whitespace) {
+            Some(format!(
+                "{} declared, but the body has unencoded whitespace: {:?}",
+                content_type, preview
+            ))
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::{StatusCode, Url};
+    use crate::{VcrRequest, VcrResponse};
+
+    fn cassette_with_request(content_type: &str, body: &str) -> VcrCassette {
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_owned(),
+            vec![content_type.to_owned()]
+        );
+
+        let mut cassette = VcrCassette::default();
+        cassette.push(
+            VcrRequest {
+                method: Method::Post,
+                url: Url::parse("https://example.com/submit").unwrap(),
+                headers,
+                body: Body::Str(body.to_owned()),
+            },
+            VcrResponse {
+                status: StatusCode::Ok,
+                headers: HashMap::new(),
+                body: Body::Str("".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        cassette
+    }
+
+    #[test]
+    fn consistent_content_types() {
+        cassette_with_request("application/json; charset=utf-8", r#"{"a":1}"#)
+            .assert_content_types_consistent();
+        cassette_with_request("application/x-www-form-urlencoded", "a=1&b=2")
+            .assert_content_types_consistent();
+    }
+
+    #[test]
+    #[should_panic(expected = "#0 request: application/json declared")]
+    fn invalid_json_body() {
+        cassette_with_request("application/json", "a=1&b=2")
+            .assert_content_types_consistent();
+    }
+
+    #[test]
+    #[should_panic(expected = "but the body is JSON")]
+    fn json_form_body() {
+        cassette_with_request(
+            "application/x-www-form-urlencoded",
+            r#"{"a":1}"#
+        ).assert_content_types_consistent();
+    }
+
+    #[test]
+    fn non_empty_bodies() {
+        let mut cassette = cassette_with_request("text/plain", "a");
+        cassette.responses[0].status = StatusCode::NoContent;
+        cassette.assert_no_empty_bodies();
+
+        cassette.requests[0].method = Method::Get;
+        cassette.requests[0].body = Body::Str("".to_owned());
+        cassette.responses[0].status = StatusCode::Ok;
+        cassette.responses[0].body = Body::Bytes(vec![0]);
+        cassette.assert_no_empty_bodies();
+    }
+
+    #[test]
+    #[should_panic(expected = "#0 POST https://example.com/submit (200): \
+        empty response body")]
+    fn empty_response_body() {
+        cassette_with_request("text/plain", "a").assert_no_empty_bodies();
+    }
+
+    #[test]
+    #[should_panic(expected = "empty request body")]
+    fn empty_request_body() {
+        let mut cassette = cassette_with_request("text/plain", "");
+        cassette.responses[0].body = Body::Str("ok".to_owned());
+        cassette.assert_no_empty_bodies();
+    }
+
+    #[test]
+    fn at_least_one_of_methods() {
+        cassette_with_request("text/plain", "")
+            .assert_at_least_one_of(&[Method::Post, Method::Put]);
+    }
+
+    #[test]
+    #[should_panic(expected = "uses any of: PUT, PATCH")]
+    fn none_of_methods() {
+        cassette_with_request("text/plain", "")
+            .assert_at_least_one_of(&[Method::Put, Method::Patch]);
+    }
+}
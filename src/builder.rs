@@ -0,0 +1,240 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+
+use crate::{
+    HeaderFilter,
+    ReplayStrategy,
+    UnmatchedBehavior,
+    VcrError,
+    VcrFormat,
+    VcrMatchOptions,
+    VcrMiddleware,
+    VcrMode,
+};
+
+
+/// Configures a [VcrMiddleware] before its cassette is loaded.
+///
+/// Options that aren't set keep the defaults of [VcrMiddleware::new]; every
+/// other option can still be set on the built middleware.
+///
+/// ```
+/// # async fn create() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::{
+///     MatchStrategy,
+///     ReplayStrategy,
+///     UnmatchedBehavior,
+///     VcrMiddlewareBuilder,
+///     VcrMode,
+/// };
+///
+/// let vcr = VcrMiddlewareBuilder::new(
+///     VcrMode::Replay,
+///     "test-sessions/simple.yml"
+/// )
+///     .match_options(MatchStrategy::MethodAndUrl.into())
+///     .filter_headers(vec!["authorization".to_owned()])
+///     .on_unmatched(UnmatchedBehavior::Passthrough)
+///     .replay_strategy(ReplayStrategy::Sequential)
+///     .build().await?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug)]
+pub struct VcrMiddlewareBuilder {
+    mode: VcrMode,
+    path: PathBuf,
+    format: Option<VcrFormat>,
+    match_options: Option<VcrMatchOptions>,
+    header_filter: Option<HeaderFilter>,
+    unmatched: Option<UnmatchedBehavior>,
+    replay_strategy: Option<ReplayStrategy>,
+    failure_artifact_dir: Option<PathBuf>,
+    inline_redirects: bool,
+    track_usage: bool,
+    dedup_bodies: bool,
+}
+
+impl VcrMiddlewareBuilder {
+    pub fn new<P>(mode: VcrMode, path: P) -> Self
+        where P: Into<PathBuf>,
+    {
+        Self {
+            mode,
+            path: path.into(),
+            format: None,
+            match_options: None,
+            header_filter: None,
+            unmatched: None,
+            replay_strategy: None,
+            failure_artifact_dir: None,
+            inline_redirects: false,
+            track_usage: false,
+            dedup_bodies: false,
+        }
+    }
+
+    /// Choose the fields of a request that are compared when searching for a
+    /// recorded request; see [VcrMiddleware::with_match_options].
+    pub fn match_options(mut self, opts: VcrMatchOptions) -> Self {
+        self.match_options = Some(opts);
+        self
+    }
+
+    /// Read and record the cassette in the given format, rather than the
+    /// format detected from the file; see [VcrMiddleware::new_with_format].
+    pub fn cassette_format(mut self, format: VcrFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Replace the values of the named headers with
+    /// [REDACTED](crate::REDACTED) before interactions are recorded; see
+    /// [VcrMiddleware::with_header_filter].
+    pub fn filter_headers(mut self, names: Vec<String>) -> Self {
+        self.header_filter = Some(HeaderFilter::new(names));
+        self
+    }
+
+    /// Choose how to respond to a request that isn't in the cassette; see
+    /// [VcrMiddleware::with_unmatched_behavior].
+    pub fn on_unmatched(mut self, behavior: UnmatchedBehavior) -> Self {
+        self.unmatched = Some(behavior);
+        self
+    }
+
+    /// Choose how recorded interactions are found for replay; see
+    /// [VcrMiddleware::with_replay_strategy].
+    pub fn replay_strategy(mut self, strategy: ReplayStrategy) -> Self {
+        self.replay_strategy = Some(strategy);
+        self
+    }
+
+    /// Write a JSON description of each replay failure to `dir`; see
+    /// [VcrMiddleware::with_failure_artifacts].
+    pub fn failure_artifact_dir<P>(mut self, dir: P) -> Self
+        where P: Into<PathBuf>,
+    {
+        self.failure_artifact_dir = Some(dir.into());
+        self
+    }
+
+    /// Replay the end of a recorded redirect chain in place of its first
+    /// redirect; see [VcrMiddleware::inline_redirects].
+    pub fn inline_redirects(mut self, inline: bool) -> Self {
+        self.inline_redirects = inline;
+        self
+    }
+
+    /// Record the time each interaction is replayed in the cassette; see
+    /// [VcrMiddleware::track_usage].
+    pub fn track_usage(mut self, track: bool) -> Self {
+        self.track_usage = track;
+        self
+    }
+
+    /// Store each recorded response body only once in the cassette; see
+    /// [VcrMiddleware::dedup_bodies].
+    pub fn dedup_bodies(mut self, dedup: bool) -> Self {
+        self.dedup_bodies = dedup;
+        self
+    }
+
+    /// Create the middleware, loading its cassette if it replays one.
+    pub async fn build(self) -> Result<VcrMiddleware, VcrError> {
+        let mut vcr = VcrMiddleware::open(self.mode, self.path, self.format)
+            .await?;
+
+        if let Some(opts) = self.match_options {
+            vcr = vcr.with_match_options(opts);
+        }
+
+        if let Some(filter) = self.header_filter {
+            vcr = vcr.with_header_filter(filter);
+        }
+
+        if let Some(behavior) = self.unmatched {
+            vcr = vcr.with_unmatched_behavior(behavior);
+        }
+
+        if let Some(strategy) = self.replay_strategy {
+            vcr = vcr.with_replay_strategy(strategy);
+        }
+
+        if let Some(dir) = self.failure_artifact_dir {
+            vcr = vcr.with_failure_artifacts(dir);
+        }
+
+        Ok(vcr.inline_redirects(self.inline_redirects)
+            .track_usage(self.track_usage)
+            .dedup_bodies(self.dedup_bodies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::StatusCode;
+    use crate::{tests::Scratch, MatchStrategy, VcrCassette, REDACTED};
+
+    #[async_std::test]
+    async fn chain_replay_options() -> Result<(), VcrError> {
+        let vcr = VcrMiddlewareBuilder::new(
+            VcrMode::Replay,
+            "test-sessions/simple.json"
+        )
+            .cassette_format(VcrFormat::Json)
+            .match_options(MatchStrategy::MethodAndUrl.into())
+            .replay_strategy(ReplayStrategy::Sequential)
+            .on_unmatched(UnmatchedBehavior::Error(StatusCode::NotFound))
+            .build().await?;
+
+        let client = surf::Client::new().with(vcr);
+
+        // Bodies aren't compared, and interactions are replayed in order.
+        let expected = ["A Response", "A Response", "And Another Response"];
+        for expected in expected.iter() {
+            let mut res = client.get("https://example.com")
+                .body_string("anything".to_owned())
+                .await.unwrap();
+            assert_eq!(res.body_string().await.unwrap(), *expected);
+        }
+
+        let res = client.get("https://example.com").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn chain_recording_options() -> Result<(), VcrError> {
+        let path = "test-sessions/scratch/builder-test.cassette";
+        let _path = Scratch::new(path);
+
+        let outer = VcrMiddlewareBuilder::new(
+            VcrMode::Replay,
+            "test-sessions/simple.yml"
+        ).build().await?;
+
+        let vcr = VcrMiddlewareBuilder::new(VcrMode::Record, path)
+            .cassette_format(VcrFormat::Json)
+            .filter_headers(vec!["x-some-header".to_owned()])
+            .build().await?;
+
+        surf::Client::new().with(vcr).with(outer)
+            .get("https://example.com")
+            .header("X-some-header", "another hello")
+            .await.unwrap();
+
+        let cassette = VcrCassette::load_as(path, VcrFormat::Json).await?;
+
+        assert_eq!(
+            cassette.requests[0].headers["x-some-header"],
+            vec![REDACTED.to_owned()]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use crate::{VcrCassette, VcrResponse};
+
+
+/// Response headers describing capabilities that replay does not reproduce.
+///
+/// - `content-encoding`: the body is replayed as recorded. The header is not
+///   recorded when the HTTP client has decoded the body, but an encoding
+///   whose decoded form can't be recognized is reported.
+/// - `trailer`: trailers are not recorded.
+/// - `transfer-encoding`: replayed bodies are never chunked.
+/// - `upgrade`: connections are never upgraded (e.g., to a WebSocket).
+///
+/// The `transfer-encoding` and `upgrade` headers can be safely removed from
+/// replayed responses; see `VcrMiddleware::auto_fix_capability_headers`.
+pub const CAPABILITY_HEADERS: &[&str] = &[
+    "content-encoding",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// The capability headers that are safe to remove from a replayed response.
+pub(crate) const FIXABLE_HEADERS: &[&str] = &["transfer-encoding", "upgrade"];
+
+/// A recorded response has a header describing a capability that replay does
+/// not reproduce.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapabilityWarning {
+    /// The position of the interaction in the cassette.
+    pub index: usize,
+    pub header: String,
+    pub value: String,
+}
+
+impl fmt::Display for CapabilityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,
+            "Recorded response #{} has a header replay cannot honor: {}: {}",
+            self.index, self.header, self.value)
+    }
+}
+
+impl VcrCassette {
+    /// List the recorded responses with headers describing capabilities that
+    /// replay does not reproduce (see [CAPABILITY_HEADERS]).
+    ///
+    /// Headers named in `suppress` (case-insensitive) are not reported.
+    pub fn capability_warnings(&self, suppress: &[&str])
+    -> Vec<CapabilityWarning> {
+        self.responses.iter()
+            .enumerate()
+            .flat_map(|(i, res)| response_warnings(i, res, suppress))
+            .collect()
+    }
+}
+
+pub(crate) fn response_warnings(
+    index: usize,
+    res: &VcrResponse,
+    suppress: &[&str]
+) -> Vec<CapabilityWarning> {
+    let mut warnings = res.headers.iter()
+        .filter(|(name, _)| {
+            CAPABILITY_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+                && ! suppress.iter().any(|h| h.eq_ignore_ascii_case(name))
+        })
+        .flat_map(|(name, values)| {
+            values.iter()
+                .filter(|v| ! v.eq_ignore_ascii_case("identity"))
+                .map(move |v| CapabilityWarning {
+                    index,
+                    header: name.to_owned(),
+                    value: v.to_owned(),
+                })
+        })
+        .collect::<Vec<_>>();
+
+    warnings.sort_by(|a, b| a.header.cmp(&b.header));
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::{http::Method, StatusCode, Url};
+    use crate::{Body, VcrRequest};
+
+    fn cassette_with_headers(headers: &[(&str, &str)]) -> VcrCassette {
+        let mut cassette = VcrCassette::default();
+
+        cassette.push(
+            VcrRequest::new(
+                Method::Get,
+                Url::parse("https://example.com").unwrap()
+            ),
+            VcrResponse {
+                status: StatusCode::Ok,
+                headers: headers.iter()
+                    .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+                    .collect(),
+                body: Body::Str("".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        cassette
+    }
+
+    #[test]
+    fn detect_capability_headers() {
+        let cassette = cassette_with_headers(&[
+            ("Upgrade", "websocket"),
+            ("transfer-encoding", "chunked"),
+            ("content-encoding", "identity"),
+            ("content-type", "text/plain"),
+        ]);
+
+        assert_eq!(cassette.capability_warnings(&[]), vec![
+            CapabilityWarning {
+                index: 0,
+                header: "Upgrade".to_owned(),
+                value: "websocket".to_owned(),
+            },
+            CapabilityWarning {
+                index: 0,
+                header: "transfer-encoding".to_owned(),
+                value: "chunked".to_owned(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn suppress_capability_warnings() {
+        let cassette = cassette_with_headers(&[
+            ("upgrade", "websocket"),
+            ("Transfer-Encoding", "chunked"),
+        ]);
+
+        let warnings = cassette.capability_warnings(&["transfer-encoding"]);
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].header, "upgrade");
+    }
+}
@@ -0,0 +1,1884 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{self, Read},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::{fs, io::SeekFrom, prelude::*};
+use http_types::{Method, Url};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    core::Session,
+    without_fragment,
+    Body,
+    SerdeWrapper,
+    VcrError,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+// A cassette file is a sequence of YAML documents. Most are request/response
+// pairs; a cassette with deduplicated bodies also stores the shared bodies,
+// keyed by their SHA-256 hash, and a cassette may begin with its metadata.
+//
+// A cassette with deduplicated headers stores a table of the shared header
+// sets before its interactions; a request or response replaces its `headers`
+// field with a `headers_ref` field holding the set's index in the table.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Document {
+    Interaction(Box<SerdeWrapper>, Box<SerdeWrapper>),
+    SharedBodies { shared_bodies: BTreeMap<String, Body> },
+    SharedHeaders { shared_headers: Vec<Headers> },
+    Metadata { metadata: CassetteMetadata },
+}
+
+type Headers = HashMap<String, Vec<String>>;
+
+/// Information describing a cassette, stored at the top of the cassette file.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CassetteMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// The time the cassette was created, preferably in RFC 3339 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Interactions that must be replayed before others; see
+    /// [VcrCassette::require_order].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<OrderConstraint>,
+}
+
+/// A requirement that the interaction at position `before` in a cassette is
+/// replayed before the interaction at position `after`.
+///
+/// Constraints form a partial order: interactions that are not constrained
+/// may be replayed in any order, and an interaction only needs to have been
+/// replayed once to satisfy the constraints on it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct OrderConstraint {
+    pub before: usize,
+    pub after: usize,
+}
+
+
+/// A recorded HTTP session.
+///
+/// A cassette is a list of requests and the responses to them, in the order
+/// they were recorded.
+///
+/// ```
+/// # async fn load() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::VcrCassette;
+///
+/// let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+/// assert_eq!(cassette.len(), 3);
+/// # Ok(()) }
+/// ```
+// Requests and responses are stored as a pair of vecs; cassettes being
+// replayed are indexed by method and URL (see IndexedCassette).
+#[derive(Clone, Debug, Default)]
+pub struct VcrCassette {
+    pub(crate) requests: Vec<VcrRequest>,
+    pub(crate) responses: Vec<VcrResponse>,
+    dedup_bodies: bool,
+    dedup_headers: bool,
+    metadata: Option<CassetteMetadata>,
+    // The directory of the file the cassette was loaded from; the files of
+    // bodies stored separately are relative to it.
+    dir: Option<PathBuf>,
+    // The format of the file the cassette was loaded from.
+    format: Option<VcrFormat>,
+}
+
+// Cassettes are compared by their contents, regardless of the file they were
+// loaded from.
+impl PartialEq for VcrCassette {
+    fn eq(&self, other: &Self) -> bool {
+        self.requests == other.requests
+            && self.responses == other.responses
+            && self.dedup_bodies == other.dedup_bodies
+            && self.dedup_headers == other.dedup_headers
+            && self.metadata == other.metadata
+    }
+}
+
+impl Eq for VcrCassette {}
+
+/// The file formats of cassettes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum VcrFormat {
+    /// A sequence of YAML documents, one per interaction.
+    Yaml,
+    /// A single JSON array of `{"request": ..., "response": ...}` objects.
+    ///
+    /// JSON cassettes don't store metadata, and bodies and headers are never
+    /// deduplicated.
+    Json,
+}
+
+// A cassette being replayed, with the positions of its requests indexed by
+// method and URL so a lookup only compares the requests that could match.
+// Positions with the same key are kept in recorded order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct IndexedCassette {
+    cassette: VcrCassette,
+    index: HashMap<(String, Url), Vec<usize>>,
+}
+
+impl IndexedCassette {
+    // The positions of the requests with the request's method and URL; the
+    // query and fragment are not compared.
+    pub(crate) fn candidates(&self, request: &VcrRequest) -> &[usize] {
+        self.index.get(&index_key(request))
+            .map_or(&[], |positions| positions.as_slice())
+    }
+
+    pub(crate) fn push(&mut self, request: VcrRequest, response: VcrResponse) {
+        self.index.entry(index_key(&request))
+            .or_default()
+            .push(self.cassette.len());
+
+        self.cassette.push(request, response);
+    }
+
+    // Replace the response of the last interaction, whose request was
+    // recorded again.
+    pub(crate) fn replace_last(&mut self, response: VcrResponse) {
+        if let Some(last) = self.cassette.responses.last_mut() {
+            *last = response;
+        }
+    }
+}
+
+impl From<VcrCassette> for IndexedCassette {
+    fn from(cassette: VcrCassette) -> Self {
+        let mut index = HashMap::<_, Vec<_>>::new();
+
+        for (pos, req) in cassette.requests.iter().enumerate() {
+            index.entry(index_key(req)).or_default().push(pos);
+        }
+
+        Self { cassette, index }
+    }
+}
+
+impl Deref for IndexedCassette {
+    type Target = VcrCassette;
+
+    fn deref(&self) -> &VcrCassette { &self.cassette }
+}
+
+fn index_key(request: &VcrRequest) -> (String, Url) {
+    let mut url = without_fragment(&request.url);
+    url.set_query(None);
+
+    (request.method.to_string(), url)
+}
+
+/// A cassette in memory that can be shared by many middlewares without
+/// reading its file again; see `VcrMiddleware::from_memory`.
+///
+/// The shared cassette is immutable: cloning a `MemoryCassette` is cheap and
+/// every clone refers to the same data. Middlewares replaying it don't modify
+/// it, so it can be shared by tests running in parallel.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryCassette(pub(crate) Arc<IndexedCassette>);
+
+impl Deref for MemoryCassette {
+    type Target = VcrCassette;
+
+    fn deref(&self) -> &VcrCassette { &self.0 }
+}
+
+impl VcrCassette {
+    /// Read a cassette from the specified file.
+    pub async fn load<P>(path: P) -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        Self::load_as(path, Self::detect_format(path)?).await
+    }
+
+    /// Read a cassette in the given format from the specified file.
+    pub async fn load_as<P>(path: P, format: VcrFormat)
+    -> Result<Self, VcrError>
+        where P: AsRef<Path>,
+    {
+        let replays = fs::read_to_string(path.as_ref()).await?;
+
+        let mut cassette = match format {
+            VcrFormat::Yaml => Self::from_yaml(&replays)?,
+            VcrFormat::Json => Self::from_json(&replays)?,
+        };
+
+        cassette.dir = path.as_ref().parent().map(Path::to_owned);
+        cassette.format = Some(format);
+        Ok(cassette)
+    }
+
+    /// Determine the format of a cassette file.
+    ///
+    /// The format is determined by the file's extension if it has a known one;
+    /// otherwise the beginning of the file is examined.
+    pub fn detect_format(path: &Path) -> Result<VcrFormat, VcrError> {
+        let ext = path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("yml") | Some("yaml") => return Ok(VcrFormat::Yaml),
+            Some("json") => return Ok(VcrFormat::Json),
+            _ => {},
+        }
+
+        let mut magic = Vec::with_capacity(16);
+        std::fs::File::open(path)?.take(16).read_to_end(&mut magic)?;
+
+        let start = magic.iter()
+            .position(|b| ! b.is_ascii_whitespace())
+            .map(|i| &magic[i..])
+            .unwrap_or(&[]);
+
+        if start.starts_with(b"---") || start.starts_with(b"- ") {
+            Ok(VcrFormat::Yaml)
+        } else if start.starts_with(b"[") {
+            Ok(VcrFormat::Json)
+        } else {
+            Err(VcrError::Format(path.to_owned()))
+        }
+    }
+
+    pub(crate) fn from_yaml(replays: &str) -> Result<Self, VcrError> {
+        let mut cassette = Self::default();
+        let mut shared = BTreeMap::new();
+        let mut shared_headers: Vec<Headers> = vec![];
+
+        for replay in replays.split("\n---\n") {
+            let document = if shared_headers.is_empty() {
+                serde_yaml::from_str(replay)?
+            } else {
+                let mut document = serde_yaml::from_str(replay)?;
+                resolve_headers(&mut document, &shared_headers)?;
+                serde_yaml::from_value(document)?
+            };
+
+            match document {
+                Document::Interaction(request, response) => {
+                    let mut req = match *request {
+                        SerdeWrapper::Request(r) => r,
+                        _ => panic!("Invalid request"),
+                    };
+                    // Older versions recorded URL fragments.
+                    req.url = without_fragment(&req.url);
+                    let resp = match *response {
+                        SerdeWrapper::Response(r) => r,
+                        _ => panic!("Invalid response"),
+                    };
+
+                    cassette.push(req, resp);
+                },
+                Document::SharedBodies { shared_bodies } => {
+                    cassette.dedup_bodies = true;
+                    shared.extend(shared_bodies);
+                },
+                Document::SharedHeaders { shared_headers: table } => {
+                    cassette.dedup_headers = true;
+                    shared_headers = table;
+                },
+                Document::Metadata { metadata } => {
+                    cassette.metadata = Some(metadata);
+                },
+            }
+        }
+
+        for response in cassette.responses.iter_mut() {
+            if let Body::Ref { body_ref } = &response.body {
+                let body = shared.get(body_ref)
+                    .cloned()
+                    .ok_or_else(|| VcrError::MissingBody(body_ref.to_owned()))?;
+
+                response.body = body;
+            }
+        }
+
+        Ok(cassette)
+    }
+
+    /// Serialize the cassette to YAML, in the format used by the middleware.
+    pub fn to_yaml(&self) -> Result<String, VcrError> {
+        let mut responses = self.responses.clone();
+        let mut yaml = String::new();
+
+        if let Some(metadata) = &self.metadata {
+            yaml.push_str(&to_document(&Document::Metadata {
+                metadata: metadata.clone(),
+            })?);
+        }
+
+        if self.dedup_bodies {
+            let shared_bodies = share_repeated_bodies(&mut responses);
+
+            if ! shared_bodies.is_empty() {
+                yaml.push_str(
+                    &to_document(&Document::SharedBodies { shared_bodies })?
+                );
+            }
+        }
+
+        let shared_headers = if self.dedup_headers {
+            share_repeated_headers(&self.requests, &responses)
+        } else {
+            vec![]
+        };
+
+        if ! shared_headers.is_empty() {
+            yaml.push_str(&to_document(&Document::SharedHeaders {
+                shared_headers: shared_headers.clone(),
+            })?);
+        }
+
+        for (req, res) in self.requests.iter().zip(responses) {
+            let interaction = Document::Interaction(
+                Box::new(SerdeWrapper::Request(req.clone())),
+                Box::new(SerdeWrapper::Response(res))
+            );
+
+            if shared_headers.is_empty() {
+                yaml.push_str(&to_document(&interaction)?);
+            } else {
+                let mut document = serde_yaml::to_value(&interaction)?;
+                reference_headers(&mut document, &shared_headers)?;
+                yaml.push_str(&to_document(&document)?);
+            }
+        }
+
+        Ok(yaml)
+    }
+
+    pub(crate) fn from_json(replays: &str) -> Result<Self, VcrError> {
+        let mut cassette = Self::default();
+
+        let interactions =
+            serde_json::from_str::<Vec<JsonInteraction>>(replays)?;
+
+        for JsonInteraction { mut request, response } in interactions {
+            // Older versions recorded URL fragments.
+            request.url = without_fragment(&request.url);
+            cassette.push(request, response);
+        }
+
+        Ok(cassette)
+    }
+
+    /// Serialize the cassette to JSON, in the format used by the middleware
+    /// (see [VcrFormat::Json]).
+    pub fn to_json(&self) -> Result<String, VcrError> {
+        let interactions = self.requests.iter()
+            .zip(self.responses.iter())
+            .map(|(req, res)| to_json_interaction(req, res))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if interactions.is_empty() {
+            Ok("[\n]\n".to_owned())
+        } else {
+            Ok(format!("[\n{}\n]\n", interactions.join(",\n")))
+        }
+    }
+
+    /// Write the cassette to the specified file, replacing its contents.
+    ///
+    /// The cassette is written in the format detected from the file (see
+    /// [VcrCassette::detect_format]), or as YAML if it can't be detected.
+    pub async fn save<P>(&self, path: P) -> Result<(), VcrError>
+        where P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let format = Self::detect_format(path).unwrap_or(VcrFormat::Yaml);
+
+        self.save_as(path, format).await
+    }
+
+    /// Write the cassette to the specified file in the given format,
+    /// replacing its contents.
+    pub async fn save_as<P>(&self, path: P, format: VcrFormat)
+    -> Result<(), VcrError>
+        where P: AsRef<Path>,
+    {
+        let contents = match format {
+            VcrFormat::Yaml => self.to_yaml()?,
+            VcrFormat::Json => self.to_json()?,
+        };
+
+        fs::write(path.as_ref(), contents).await?;
+        Ok(())
+    }
+
+    /// Move the cassette into a [MemoryCassette] to share it across
+    /// middlewares.
+    pub fn into_memory(self) -> MemoryCassette {
+        MemoryCassette(Arc::new(self.into()))
+    }
+
+    /// Store identical response bodies only once when saving the cassette.
+    ///
+    /// Each body that occurs more than once is written to a `shared_bodies`
+    /// document keyed by its SHA-256 hash; the responses reference it by that
+    /// hash (`body: {body_ref: <hash>}`). References are resolved when the
+    /// cassette is loaded, so deduplication is transparent to replays.
+    ///
+    /// Cassettes loaded from a file with shared bodies are deduplicated by
+    /// default.
+    pub fn dedup_bodies(mut self) -> Self {
+        self.dedup_bodies = true;
+        self
+    }
+
+    /// Store identical sets of headers only once when saving the cassette.
+    ///
+    /// Each set of request or response headers that occurs more than once is
+    /// written to a `shared_headers` document, a list of header sets, before
+    /// the interactions. The requests and responses reference a set by its
+    /// position in the list (`headers_ref: <index>`) instead of listing their
+    /// headers. References are resolved when the cassette is loaded, so
+    /// deduplication is transparent to replays.
+    ///
+    /// Cassettes loaded from a file with shared headers are deduplicated by
+    /// default.
+    pub fn dedup_headers(mut self) -> Self {
+        self.dedup_headers = true;
+        self
+    }
+
+    /// Create a cassette from request/response pairs, in the order they are
+    /// given.
+    pub fn build_from_pairs<I>(pairs: I) -> VcrCassette
+        where I: IntoIterator<Item = (VcrRequest, VcrResponse)>,
+    {
+        let (requests, responses) = pairs.into_iter().unzip();
+
+        VcrCassette {
+            requests,
+            responses,
+            ..VcrCassette::default()
+        }
+    }
+
+    // The path of a body stored in a separate file.
+    pub(crate) fn body_path(&self, file: &Path) -> PathBuf {
+        match &self.dir {
+            Some(dir) => dir.join(file),
+            None => file.to_owned(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, request: VcrRequest, response: VcrResponse) {
+        self.requests.push(request);
+        self.responses.push(response);
+    }
+
+    /// Attach descriptive metadata to the cassette, replacing any existing
+    /// metadata.
+    pub fn with_metadata(mut self, meta: CassetteMetadata) -> Self {
+        self.metadata = Some(meta);
+        self
+    }
+
+    /// The cassette's metadata, if any.
+    pub fn metadata(&self) -> Option<&CassetteMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Require the interaction at position `before` to be replayed before the
+    /// interaction at position `after`, such as to require fetching a token
+    /// before the resource it protects.
+    ///
+    /// Replaying `after` first fails with [VcrError::OrderViolation]. The
+    /// constraint is stored in the cassette's metadata.
+    ///
+    /// This only checks dependencies between interactions; to replay
+    /// different responses to the same request as a session progresses,
+    /// record the request once for each response.
+    ///
+    /// ```
+    /// # async fn constrain() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?
+    ///     .require_order(0, 2);
+    /// # Ok(()) }
+    /// ```
+    pub fn require_order(mut self, before: usize, after: usize) -> Self {
+        self.metadata.get_or_insert_with(CassetteMetadata::default)
+            .order
+            .push(OrderConstraint { before, after });
+        self
+    }
+
+    // The position of an interaction that must be replayed before the
+    // interaction at `pos` but has not been, according to `played`.
+    pub(crate) fn unmet_dependency(&self, pos: usize, played: &BTreeSet<usize>)
+    -> Option<usize> {
+        self.metadata.as_ref()?
+            .order
+            .iter()
+            .find(|c| c.after == pos && ! played.contains(&c.before))
+            .map(|c| c.before)
+    }
+
+    /// Generate a human-readable report describing the cassette.
+    pub fn summarize(&self) -> String {
+        let mut summary = format!("Interactions: {}\n", self.len());
+
+        if let Some(meta) = &self.metadata {
+            if let Some(description) = &meta.description {
+                summary.push_str(&format!("Description: {}\n", description));
+            }
+            if let Some(author) = &meta.author {
+                summary.push_str(&format!("Author: {}\n", author));
+            }
+            if let Some(created_at) = &meta.created_at {
+                summary.push_str(&format!("Created: {}\n", created_at));
+            }
+            if let Some(version) = meta.schema_version {
+                summary.push_str(&format!("Schema version: {}\n", version));
+            }
+            if ! meta.tags.is_empty() {
+                summary.push_str(&format!("Tags: {}\n", meta.tags.join(", ")));
+            }
+        }
+
+        summary
+    }
+
+    /// Create a copy of the cassette with every request and response body
+    /// longer than `max_bytes` truncated, for previews in documentation or
+    /// code review.
+    ///
+    /// Truncated bodies end with a `[TRUNCATED at N bytes]` marker; text is
+    /// cut at the last character boundary before `max_bytes`. Headers are not
+    /// changed, so a truncated cassette may not replay correctly unless its
+    /// [content lengths](VcrCassette::upgrade_content_lengths) are updated.
+    /// Bodies stored in separate files are not truncated.
+    pub fn to_truncated_bodies(&self, max_bytes: usize) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, res) in cassette.entries_mut() {
+            truncate_body(&mut req.body, max_bytes);
+            truncate_body(&mut res.body, max_bytes);
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with only the entries for which `keep`
+    /// returns `true`, in the same order.
+    ///
+    /// Order constraints (see [require_order](VcrCassette::require_order))
+    /// between kept entries are renumbered; others are dropped.
+    pub fn filter<F>(&self, keep: F) -> VcrCassette
+        where F: Fn(&VcrRequest, &VcrResponse) -> bool,
+    {
+        let mut cassette = VcrCassette {
+            requests: vec![],
+            responses: vec![],
+            dedup_bodies: self.dedup_bodies,
+            dedup_headers: self.dedup_headers,
+            metadata: self.metadata.clone(),
+            dir: self.dir.clone(),
+            format: self.format,
+        };
+        let mut positions = HashMap::new();
+
+        for (pos, (req, res)) in self.requests.iter()
+            .zip(self.responses.iter())
+            .enumerate()
+        {
+            if keep(req, res) {
+                positions.insert(pos, cassette.len());
+                cassette.push(req.clone(), res.clone());
+            }
+        }
+
+        if let Some(meta) = &mut cassette.metadata {
+            meta.order = meta.order.iter()
+                .filter_map(|c| Some(OrderConstraint {
+                    before: *positions.get(&c.before)?,
+                    after: *positions.get(&c.after)?,
+                }))
+                .collect();
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with only the entries whose request URL
+    /// path starts with `prefix`, such as to extract the requests to one
+    /// service; see [filter](VcrCassette::filter).
+    ///
+    /// ```
+    /// # async fn extract() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+    /// let api = cassette.filter_by_path_prefix("/api/v2/");
+    /// # Ok(()) }
+    /// ```
+    pub fn filter_by_path_prefix(&self, prefix: &str) -> VcrCassette {
+        self.filter(|req, _| req.url.path().starts_with(prefix))
+    }
+
+    /// Set the `Content-Length` header of every request and response to the
+    /// length of its body, after the bodies were modified by redaction,
+    /// truncation, or normalization.
+    ///
+    /// Only existing headers are updated; bodies stored in separate files are
+    /// not measured, so their headers are left as they are.
+    pub fn upgrade_content_lengths(&mut self) {
+        for (req, res) in self.entries_mut() {
+            update_content_length(&mut req.headers, &req.body);
+            update_content_length(&mut res.headers, &res.body);
+        }
+    }
+
+    /// Remove the interactions that have not been replayed since `cutoff`,
+    /// returning them in the order they were recorded.
+    ///
+    /// Replays are only known for cassettes replayed with usage tracking (see
+    /// `VcrMiddleware::track_usage`); interactions that have never been
+    /// tracked as replayed are removed.
+    pub fn prune_unplayed_since(&mut self, cutoff: SystemTime)
+    -> Vec<(VcrRequest, VcrResponse)> {
+        let cutoff = cutoff.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let requests = std::mem::take(&mut self.requests);
+        let responses = std::mem::take(&mut self.responses);
+        let mut removed = vec![];
+
+        for (req, res) in requests.into_iter().zip(responses) {
+            if res.last_played_at.is_some_and(|t| t >= cutoff) {
+                self.push(req, res);
+            } else {
+                removed.push((req, res));
+            }
+        }
+
+        removed
+    }
+
+    /// The number of request/response pairs in the cassette.
+    pub fn len(&self) -> usize { self.requests.len() }
+
+    /// Returns `true` if the cassette has no recorded requests.
+    pub fn is_empty(&self) -> bool { self.requests.is_empty() }
+
+    /// Retrieve the request and response at the specified position.
+    pub fn entry_at(&self, index: usize)
+    -> Option<(&VcrRequest, &VcrResponse)> {
+        self.requests.get(index).zip(self.responses.get(index))
+    }
+
+    /// Retrieve the request at the specified position.
+    pub fn request_at_index(&self, index: usize) -> Option<&VcrRequest> {
+        self.requests.get(index)
+    }
+
+    /// Retrieve the response at the specified position.
+    pub fn response_at_index(&self, index: usize) -> Option<&VcrResponse> {
+        self.responses.get(index)
+    }
+
+    /// Retrieve the first request with the specified URL and method.
+    pub fn request_by_url_and_method(&self, url: &Url, method: Method)
+    -> Option<&VcrRequest> {
+        self.requests.iter().find(|req| req.method == method && &req.url == url)
+    }
+
+    /// Iterate over the request and response of each entry, allowing them to
+    /// be modified.
+    pub fn entries_mut(&mut self)
+    -> impl Iterator<Item = (&mut VcrRequest, &mut VcrResponse)> {
+        self.requests.iter_mut().zip(self.responses.iter_mut())
+    }
+
+    /// The size in bytes of each entry when serialized in the cassette's
+    /// format, in the order of the entries: the format of the file it was
+    /// loaded from, or YAML for a cassette built in memory. The separators
+    /// between the entries of a JSON cassette are not counted.
+    ///
+    /// Bodies are measured in full, even when the cassette stores repeated
+    /// bodies once (see [dedup_bodies](VcrCassette::dedup_bodies)).
+    pub fn entry_byte_sizes(&self) -> Vec<usize> {
+        self.requests.iter()
+            .zip(self.responses.iter())
+            .map(|(req, res)| match self.format {
+                Some(VcrFormat::Json) => to_json_interaction(req, res)
+                    // Requests and responses always have a JSON
+                    // representation.
+                    .expect("Failed to serialize a cassette entry")
+                    .len(),
+                _ => to_document(&Document::Interaction(
+                    Box::new(SerdeWrapper::Request(req.clone())),
+                    Box::new(SerdeWrapper::Response(res.clone()))
+                ))
+                    // Requests and responses always have a YAML
+                    // representation.
+                    .expect("Failed to serialize a cassette entry")
+                    .len(),
+            })
+            .collect()
+    }
+
+    /// The method and URL of every recorded request, in the order they were
+    /// recorded.
+    pub fn coverage(&self) -> Vec<(Method, Url)> {
+        self.requests.iter()
+            .map(|req| (req.method, req.url.clone()))
+            .collect()
+    }
+
+    /// The distinct methods and URLs of the recorded requests, in the order
+    /// they were first recorded; see [coverage](VcrCassette::coverage).
+    pub fn endpoints(&self) -> Vec<(Method, Url)> {
+        let mut seen = HashSet::new();
+
+        self.coverage().into_iter()
+            .filter(|endpoint| seen.insert(endpoint.clone()))
+            .collect()
+    }
+
+    /// The distinct status codes of the recorded responses.
+    ///
+    /// ```
+    /// # async fn check() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("sessions/errors.yml").await?;
+    /// assert!(cassette.unique_response_status_codes().contains(&404));
+    /// # Ok(()) }
+    /// ```
+    pub fn unique_response_status_codes(&self) -> BTreeSet<u16> {
+        self.responses.iter()
+            .map(|res| res.status as u16)
+            .collect()
+    }
+
+    /// The fraction of responses with a successful (2xx) status, from 0.0 to
+    /// 1.0; an empty cassette has a success rate of 1.0.
+    pub fn response_success_rate(&self) -> f64 {
+        if self.responses.is_empty() {
+            return 1.0;
+        }
+
+        let successes = self.responses.iter()
+            .filter(|res| res.status.is_success())
+            .count();
+
+        successes as f64 / self.responses.len() as f64
+    }
+
+    /// The positions of the entries whose request or response has the header
+    /// with the given value, such as to audit the requests sent with a
+    /// feature flag.
+    ///
+    /// The header's name is case-insensitive; its value is compared exactly,
+    /// with each value of a repeated header compared separately.
+    ///
+    /// ```
+    /// # async fn audit() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("sessions/checkout.yml").await?;
+    /// let flagged = cassette
+    ///     .find_by_header_value("X-Feature-Flag", "enabled");
+    /// # Ok(()) }
+    /// ```
+    pub fn find_by_header_value(&self, header: &str, value: &str)
+    -> Vec<usize> {
+        let has_value = |headers: &HashMap<String, Vec<String>>| {
+            headers.iter().any(|(name, values)| {
+                name.eq_ignore_ascii_case(header)
+                    && values.iter().any(|v| v == value)
+            })
+        };
+
+        self.requests.iter()
+            .zip(self.responses.iter())
+            .enumerate()
+            .filter(|(_, (req, res))| {
+                has_value(&req.headers) || has_value(&res.headers)
+            })
+            .map(|(pos, _)| pos)
+            .collect()
+    }
+
+    /// Returns `true` if the cassette has a request that `matcher`, a
+    /// `VcrMiddleware` or a [Session], would replay in response to
+    /// `request`.
+    ///
+    /// The matcher's options, such as registered body matchers and redacted
+    /// query parameters, are used to compare the requests; correlation IDs
+    /// are not considered.
+    pub fn can_satisfy<M>(&self, request: &VcrRequest, matcher: &M) -> bool
+        where M: AsRef<Session>,
+    {
+        let session = matcher.as_ref();
+
+        self.requests.iter().any(|rec| session.request_matches(rec, request))
+    }
+
+    /// Check that a sequence of replayed requests matches the cassette's
+    /// requests in the order they were recorded.
+    ///
+    /// On failure, returns the positions in `requests` that do not match the
+    /// cassette entry at the same position, including any requests beyond the
+    /// end of the cassette.
+    pub fn replay_order_valid(&self, requests: &[VcrRequest])
+    -> Result<(), Vec<usize>> {
+        let broken = requests.iter()
+            .enumerate()
+            .filter(|(i, req)| self.requests.get(*i) != Some(*req))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        if broken.is_empty() {
+            Ok(())
+        } else {
+            Err(broken)
+        }
+    }
+}
+
+// Set the time the given interactions were last played in the cassette file.
+//
+// In a YAML cassette, only the `last_played_at` line of each interaction is
+// added or replaced, so the rest of the file is unchanged; a JSON cassette is
+// rewritten.
+pub(crate) async fn write_usage(
+    path: &Path,
+    played: &BTreeSet<usize>,
+    at: u64
+) -> Result<(), VcrError> {
+    if VcrCassette::detect_format(path)? == VcrFormat::Json {
+        let mut cassette = VcrCassette::load_as(path, VcrFormat::Json).await?;
+
+        for index in played.iter() {
+            if let Some(res) = cassette.responses.get_mut(*index) {
+                res.last_played_at = Some(at);
+            }
+        }
+
+        return cassette.save_as(path, VcrFormat::Json).await;
+    }
+
+    let text = fs::read_to_string(path).await?;
+    let mut docs = text.split("\n---\n").map(str::to_owned).collect::<Vec<_>>();
+    let mut index = 0;
+
+    for doc in docs.iter_mut() {
+        if let Document::Interaction(..) = serde_yaml::from_str(doc)? {
+            if played.contains(&index) {
+                *doc = set_last_played(doc, at);
+            }
+
+            index += 1;
+        }
+    }
+
+    fs::write(path, docs.join("\n---\n")).await?;
+    Ok(())
+}
+
+// Add or replace the `last_played_at` field of the response in a single
+// interaction document.
+fn set_last_played(doc: &str, at: u64) -> String {
+    let mut lines = doc.lines().map(str::to_owned).collect::<Vec<_>>();
+
+    let response = match lines.iter()
+        .position(|l| l.trim_start().starts_with("- Response:"))
+    {
+        Some(pos) => pos,
+        None => return doc.to_owned(),
+    };
+
+    let indent = match lines[response + 1..].iter()
+        .find(|l| ! l.trim().is_empty())
+    {
+        Some(l) => l[..l.len() - l.trim_start().len()].to_owned(),
+        None => return doc.to_owned(),
+    };
+
+    let field = format!("{}last_played_at: {}", indent, at);
+
+    let existing = lines[response + 1..].iter()
+        .position(|l| l.starts_with(&format!("{}last_played_at:", indent)));
+
+    match existing {
+        Some(pos) => lines[response + 1 + pos] = field,
+        None => {
+            let last = lines.iter()
+                .rposition(|l| ! l.trim().is_empty())
+                .unwrap_or(response);
+
+            lines.insert(last + 1, field);
+        },
+    }
+
+    let mut doc_text = lines.join("\n");
+    if doc.ends_with('\n') { doc_text.push('\n'); }
+
+    doc_text
+}
+
+// Serialize a single YAML document, ensuring it can be directly followed by
+// another.
+pub(crate) fn to_document<T>(doc: &T) -> Result<String, serde_yaml::Error>
+    where T: Serialize,
+{
+    let mut doc = serde_yaml::to_string(doc)?;
+
+    if ! doc.ends_with('\n') {
+        doc.push('\n');
+    }
+
+    Ok(doc)
+}
+
+// An interaction in a JSON cassette.
+#[derive(Deserialize)]
+struct JsonInteraction {
+    request: VcrRequest,
+    response: VcrResponse,
+}
+
+#[derive(Serialize)]
+struct JsonInteractionRef<'a> {
+    request: &'a VcrRequest,
+    response: &'a VcrResponse,
+}
+
+pub(crate) fn to_json_interaction(req: &VcrRequest, res: &VcrResponse)
+-> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&JsonInteractionRef {
+        request: req,
+        response: res,
+    })
+}
+
+// Append an interaction to a JSON cassette, after truncating the file to
+// `start` bytes, and return the offset it was written at.
+//
+// `start` is either the length of the file, or an offset returned by an
+// earlier call to replace the interactions written from there.
+pub(crate) async fn append_json(path: &Path, start: u64, interaction: &str)
+-> io::Result<u64> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(path).await?;
+
+    file.set_len(start).await?;
+
+    // The interaction replaces the array's closing bracket, if it's there.
+    let (end, separator) = if start == 0 {
+        (0, "[\n")
+    } else {
+        let tail_len = start.min(64);
+        let mut tail = vec![0; tail_len as usize];
+
+        file.seek(SeekFrom::Start(start - tail_len)).await?;
+        file.read_exact(&mut tail).await?;
+
+        let last_char = |tail: &[u8]| tail.iter()
+            .rposition(|b| ! b.is_ascii_whitespace());
+
+        let last = last_char(&tail).and_then(|i| match tail[i] {
+            b']' => last_char(&tail[..i]),
+            _ => Some(i),
+        });
+
+        match last {
+            Some(i) if tail[i] == b'[' || tail[i] == b'}' => (
+                start - tail_len + i as u64 + 1,
+                if tail[i] == b'[' { "\n" } else { ",\n" }
+            ),
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not a JSON cassette: {}", path.display())
+            )),
+        }
+    };
+
+    file.set_len(end).await?;
+    file.seek(SeekFrom::Start(end)).await?;
+    file.write_all(format!("{}{}\n]\n", separator, interaction).as_bytes())
+        .await?;
+
+    file.flush().await?;
+    Ok(end)
+}
+
+fn truncate_body(body: &mut Body, max_bytes: usize) {
+    match body {
+        Body::Str(s) if s.len() > max_bytes => {
+            let mut end = max_bytes;
+            while ! s.is_char_boundary(end) { end -= 1; }
+
+            s.truncate(end);
+            s.push_str(&format!("[TRUNCATED at {} bytes]", end));
+        },
+        Body::Bytes(b) if b.len() > max_bytes => {
+            b.truncate(max_bytes);
+            b.extend(format!("[TRUNCATED at {} bytes]", max_bytes).bytes());
+        },
+        _ => {},
+    }
+}
+
+fn update_content_length(
+    headers: &mut HashMap<String, Vec<String>>,
+    body: &Body
+) {
+    let len = match body.known_len() {
+        Some(len) => len,
+        None => return,
+    };
+
+    for (name, values) in headers.iter_mut() {
+        if name.eq_ignore_ascii_case("content-length") {
+            *values = vec![len.to_string()];
+        }
+    }
+}
+
+// Replace each body that occurs more than once with a reference to it,
+// returning the referenced bodies.
+fn share_repeated_bodies(responses: &mut [VcrResponse])
+-> BTreeMap<String, Body> {
+    let mut counts = HashMap::new();
+
+    for res in responses.iter() {
+        if let Some(hash) = body_hash(&res.body) {
+            *counts.entry(hash).or_insert(0) += 1;
+        }
+    }
+
+    let mut shared = BTreeMap::new();
+
+    for res in responses.iter_mut() {
+        if let Some(hash) = body_hash(&res.body) {
+            if counts[&hash] > 1 {
+                let body = std::mem::replace(
+                    &mut res.body,
+                    Body::Ref { body_ref: hash.clone() }
+                );
+
+                shared.insert(hash, body);
+            }
+        }
+    }
+
+    shared
+}
+
+// List the non-empty header sets that occur more than once, in the order they
+// first occur.
+fn share_repeated_headers(
+    requests: &[VcrRequest],
+    responses: &[VcrResponse]
+) -> Vec<Headers> {
+    let sets = requests.iter()
+        .zip(responses.iter())
+        .flat_map(|(req, res)| vec![&req.headers, &res.headers])
+        .filter(|headers| ! headers.is_empty())
+        .collect::<Vec<_>>();
+
+    let sorted = |headers: &Headers| headers.iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut counts = BTreeMap::new();
+    for headers in sets.iter() {
+        *counts.entry(sorted(headers)).or_insert(0) += 1;
+    }
+
+    let mut shared: Vec<Headers> = vec![];
+
+    for headers in sets {
+        if counts[&sorted(headers)] > 1 && ! shared.contains(headers) {
+            shared.push(headers.to_owned());
+        }
+    }
+
+    shared
+}
+
+// Replace the headers of the interaction's request and response with a
+// reference to their set in the table, if it's there.
+fn reference_headers(document: &mut serde_yaml::Value, table: &[Headers])
+-> Result<(), serde_yaml::Error> {
+    let key = serde_yaml::Value::from("headers");
+
+    for message in messages_mut(document) {
+        let headers: Headers = match message.get(&key) {
+            Some(headers) => serde_yaml::from_value(headers.clone())?,
+            None => continue,
+        };
+
+        if let Some(index) = table.iter().position(|h| h == &headers) {
+            let index = serde_yaml::to_value(index)?;
+            replace_field(message, "headers", "headers_ref", index);
+        }
+    }
+
+    Ok(())
+}
+
+// Replace references to shared header sets with the sets.
+fn resolve_headers(document: &mut serde_yaml::Value, table: &[Headers])
+-> Result<(), VcrError> {
+    let key = serde_yaml::Value::from("headers_ref");
+
+    for message in messages_mut(document) {
+        let index: usize = match message.get(&key) {
+            Some(index) => serde_yaml::from_value(index.clone())?,
+            None => continue,
+        };
+
+        let headers = table.get(index)
+            .ok_or(VcrError::MissingHeaders(index))?;
+
+        let headers = serde_yaml::to_value(headers)?;
+        replace_field(message, "headers_ref", "headers", headers);
+    }
+
+    Ok(())
+}
+
+// The request and response mappings of an interaction document; any other
+// document has none.
+fn messages_mut(document: &mut serde_yaml::Value)
+-> impl Iterator<Item = &mut serde_yaml::Mapping> {
+    document.as_sequence_mut()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_mapping_mut())
+        .flat_map(|item| item.iter_mut().map(|(_, message)| message))
+        .filter_map(|message| message.as_mapping_mut())
+}
+
+// Replace a field of a mapping, keeping its position.
+fn replace_field(
+    map: &mut serde_yaml::Mapping,
+    from: &str,
+    to: &str,
+    value: serde_yaml::Value
+) {
+    let mut value = Some(value);
+
+    *map = std::mem::take(map).into_iter()
+        .map(|(k, v)| match k.as_str() {
+            Some(k) if k == from => (to.into(), value.take().unwrap_or(v)),
+            _ => (k, v),
+        })
+        .collect();
+}
+
+// Replace the body of a response with a reference to it, to record it in a
+// cassette with shared bodies. Unless the body is one of those already
+// `shared`, its hash is returned with a document sharing it.
+pub(crate) fn reference_body(
+    response: &mut VcrResponse,
+    shared: &HashSet<String>
+) -> Result<Option<(String, String)>, serde_yaml::Error> {
+    let hash = match body_hash(&response.body) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    let body = std::mem::replace(
+        &mut response.body,
+        Body::Ref { body_ref: hash.clone() }
+    );
+
+    if shared.contains(&hash) {
+        return Ok(None);
+    }
+
+    let mut shared_bodies = BTreeMap::new();
+    shared_bodies.insert(hash.clone(), body);
+
+    let document = to_document(&Document::SharedBodies { shared_bodies })?;
+    Ok(Some((hash, document)))
+}
+
+fn body_hash(body: &Body) -> Option<String> {
+    let bytes = match body {
+        Body::Bytes(b) => b.as_slice(),
+        Body::Str(s) => s.as_bytes(),
+        Body::File { .. } | Body::Ref { .. } => return None,
+    };
+
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(format!("{:x}", Sha256::digest(bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::Scratch, StatusCode, VcrMiddleware};
+
+    #[test]
+    fn detect_cassette_format() -> Result<(), VcrError> {
+        let path =
+            Path::new("test-sessions/scratch/detect-format-test.cassette");
+        let _path = Scratch::new(path);
+
+        assert_eq!(
+            VcrCassette::detect_format(Path::new("test-sessions/simple.yml"))?,
+            VcrFormat::Yaml
+        );
+
+        std::fs::write(path, "\n---\n- Request:\n")?;
+        assert_eq!(VcrCassette::detect_format(path)?, VcrFormat::Yaml);
+
+        std::fs::write(path, "  [\n  {\"request\": ")?;
+        assert_eq!(VcrCassette::detect_format(path)?, VcrFormat::Json);
+        assert_eq!(
+            VcrCassette::detect_format(Path::new("missing.JSON"))?,
+            VcrFormat::Json
+        );
+
+        std::fs::write(path, "<Request/>")?;
+        assert!(matches!(
+            VcrCassette::detect_format(path),
+            Err(VcrError::Format(_))
+        ));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn split_access_matches_entries() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let (req, res) = cassette.entry_at(1).unwrap();
+        assert_eq!(cassette.request_at_index(1), Some(req));
+        assert_eq!(cassette.response_at_index(1), Some(res));
+
+        assert_eq!(cassette.request_at_index(3), None);
+        assert_eq!(cassette.response_at_index(3), None);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn replay_order_reports_out_of_order_requests()
+    -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let in_order = cassette.requests.clone();
+        assert_eq!(cassette.replay_order_valid(&in_order), Ok(()));
+
+        let swapped = vec![
+            cassette.requests[0].clone(),
+            cassette.requests[2].clone(),
+            cassette.requests[1].clone(),
+            cassette.requests[0].clone(),
+        ];
+        assert_eq!(cassette.replay_order_valid(&swapped), Err(vec![1, 2, 3]));
+
+        Ok(())
+    }
+
+    fn paginated_cassette() -> VcrCassette {
+        let template = "<header/>".repeat(1024);
+        let mut cassette = VcrCassette::default();
+
+        for page in 0..10 {
+            let url = format!("https://example.com/items?page={}", page);
+
+            cassette.push(
+                VcrRequest::new(
+                    surf::http::Method::Get,
+                    surf::Url::parse(&url).unwrap()
+                ),
+                VcrResponse {
+                    status: surf::StatusCode::Ok,
+                    headers: HashMap::new(),
+                    body: Body::Str(template.clone()),
+                    ..Default::default()
+                }
+            );
+        }
+
+        cassette
+    }
+
+    #[test]
+    fn dedup_bodies_round_trip() -> Result<(), VcrError> {
+        let cassette = paginated_cassette().dedup_bodies();
+
+        let yaml = cassette.to_yaml()?;
+        assert_eq!(yaml.matches("body_ref").count(), 10);
+        assert_eq!(VcrCassette::from_yaml(&yaml)?, cassette);
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_binary_bodies_as_base64() -> Result<(), VcrError> {
+        let legacy = "---
+- Request:
+    method: GET
+    url: \"https://example.com/\"
+    headers: {}
+    body: \"\"
+- Response:
+    status: 200
+    version: ~
+    headers: {}
+    body:
+      - 0
+      - 159
+      - 146
+      - 150
+";
+
+        let cassette = VcrCassette::from_yaml(legacy)?;
+        assert_eq!(
+            cassette.responses[0].body,
+            Body::Bytes(vec![0, 159, 146, 150])
+        );
+
+        let yaml = cassette.to_yaml()?;
+        assert!(yaml.contains("base64: AJ+Slg=="));
+        assert!(! yaml.contains("- 159"));
+        assert_eq!(VcrCassette::from_yaml(&yaml)?, cassette);
+
+        // Text bodies are still stored as strings.
+        assert!(yaml.contains("body: \"\""));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn json_round_trip() -> Result<(), VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        cassette.responses[1].body = Body::Bytes(vec![0, 159, 146, 150]);
+
+        let json = cassette.to_json()?;
+        assert!(json.contains(r#""base64": "AJ+Slg==""#));
+        assert_eq!(VcrCassette::from_json(&json)?, cassette);
+
+        assert_eq!(
+            VcrCassette::from_json(&VcrCassette::default().to_json()?)?,
+            VcrCassette::default()
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn json_and_yaml_cassettes_load_alike() -> Result<(), VcrError> {
+        let yaml = VcrCassette::load("test-sessions/simple.yml").await?;
+        let json = VcrCassette::load("test-sessions/simple.json").await?;
+
+        assert_eq!(json.len(), 3);
+        assert_eq!(json.requests, yaml.requests);
+        assert_eq!(json.responses, yaml.responses);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn append_json_interactions() -> Result<(), VcrError> {
+        let path = Path::new("test-sessions/scratch/append-json-test.json");
+        let _path = Scratch::new(path);
+
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let interaction = |i: usize| to_json_interaction(
+            &cassette.requests[i],
+            &cassette.responses[i]
+        ).unwrap();
+
+        append_json(path, 0, &interaction(0)).await?;
+        let len = fs::metadata(path).await?.len();
+        append_json(path, len, &interaction(1)).await?;
+
+        // Replace the last interaction, as when collapsing retries.
+        let len = fs::metadata(path).await?.len();
+        let start = append_json(path, len, &interaction(0)).await?;
+        append_json(path, start, &interaction(1)).await?;
+        append_json(path, start, &interaction(2)).await?;
+
+        let loaded = VcrCassette::load(path).await?;
+        assert_eq!(loaded, cassette);
+
+        // Saved cassettes can be appended to, even if they're empty.
+        VcrCassette::default().save(path).await?;
+        append_json(path, fs::metadata(path).await?.len(), &interaction(2))
+            .await?;
+        assert_eq!(VcrCassette::load(path).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_headers_round_trip() -> Result<(), VcrError> {
+        let mut cassette = paginated_cassette().dedup_headers();
+
+        for (i, (req, res)) in cassette.entries_mut().enumerate() {
+            req.headers.insert(
+                "authorization".to_owned(),
+                vec!["Bearer token".to_owned()]
+            );
+            res.headers.insert(
+                "content-type".to_owned(),
+                vec!["application/json".to_owned()]
+            );
+
+            // A set that occurs once is not shared.
+            if i == 3 {
+                res.headers.insert("x-page".to_owned(), vec!["3".to_owned()]);
+            }
+        }
+
+        let yaml = cassette.to_yaml()?;
+        assert_eq!(yaml.matches("headers_ref").count(), 19);
+        assert_eq!(yaml.matches("Bearer token").count(), 1);
+        assert_eq!(VcrCassette::from_yaml(&yaml)?, cassette);
+
+        let yaml = yaml.replace("headers_ref: 1", "headers_ref: 9");
+        assert!(matches!(
+            VcrCassette::from_yaml(&yaml),
+            Err(VcrError::MissingHeaders(9))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_bodies_reduces_size() -> Result<(), VcrError> {
+        let full = paginated_cassette().to_yaml()?;
+        let dedup = paginated_cassette().dedup_bodies().to_yaml()?;
+
+        assert!(dedup.len() * 5 < full.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_round_trip() -> Result<(), VcrError> {
+        let meta = CassetteMetadata {
+            description: Some("Paginated item listing".to_owned()),
+            author: Some("Test Author".to_owned()),
+            created_at: Some("2021-06-01T12:00:00Z".to_owned()),
+            schema_version: Some(1),
+            tags: vec!["items".to_owned(), "pagination".to_owned()],
+            order: vec![OrderConstraint { before: 0, after: 2 }],
+        };
+
+        let cassette = paginated_cassette().with_metadata(meta.clone());
+        let yaml = cassette.to_yaml()?;
+
+        assert!(yaml.starts_with("---\nmetadata:"));
+
+        let loaded = VcrCassette::from_yaml(&yaml)?;
+        assert_eq!(loaded.metadata(), Some(&meta));
+        assert_eq!(loaded, cassette);
+
+        let summary = loaded.summarize();
+        assert!(summary.contains("Interactions: 10"));
+        assert!(summary.contains("Description: Paginated item listing"));
+        assert!(summary.contains("Author: Test Author"));
+        assert!(summary.contains("Created: 2021-06-01T12:00:00Z"));
+        assert!(summary.contains("Schema version: 1"));
+        assert!(summary.contains("Tags: items, pagination"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_body_reference_is_an_error() {
+        let yaml = concat!(
+            "---\n",
+            "- Request:\n",
+            "    method: GET\n",
+            "    url: \"https://example.com/\"\n",
+            "    headers: {}\n",
+            "    body: \"\"\n",
+            "- Response:\n",
+            "    status: 200\n",
+            "    version: ~\n",
+            "    headers: {}\n",
+            "    body:\n",
+            "      body_ref: abc123\n",
+        );
+
+        match VcrCassette::from_yaml(yaml) {
+            Err(VcrError::MissingBody(hash)) => assert_eq!(hash, "abc123"),
+            res => panic!("Expected a missing body error; got {:?}", res),
+        }
+    }
+
+    #[async_std::test]
+    async fn entry_byte_sizes_match_serialized_entries()
+    -> Result<(), VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let sizes = cassette.entry_byte_sizes();
+        assert_eq!(sizes.len(), cassette.len());
+        assert_eq!(sizes.iter().sum::<usize>(), cassette.to_yaml()?.len());
+
+        for (_, res) in cassette.entries_mut() {
+            res.body = Body::Str("".to_owned());
+        }
+
+        let truncated = cassette.entry_byte_sizes();
+        assert!(truncated.iter().zip(sizes.iter()).all(|(t, s)| t <= s));
+        assert!(truncated.iter().sum::<usize>() < sizes.iter().sum::<usize>());
+
+        // JSON entries are separated by ",\n", and enclosed by "[\n" and
+        // "\n]\n".
+        let cassette = VcrCassette::load("test-sessions/simple.json").await?;
+        let sizes = cassette.entry_byte_sizes();
+        assert_eq!(
+            sizes.iter().sum::<usize>() + 2 * (sizes.len() - 1) + 5,
+            cassette.to_json()?.len()
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn truncate_bodies() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let truncated = cassette.to_truncated_bodies(4);
+
+        assert_eq!(
+            truncated.requests[0].body,
+            Body::Str("My R[TRUNCATED at 4 bytes]".to_owned())
+        );
+        assert_eq!(
+            truncated.responses[0].body,
+            Body::Str("A Re[TRUNCATED at 4 bytes]".to_owned())
+        );
+        assert_eq!(
+            cassette.responses[0].body,
+            Body::Str("A Response".to_owned())
+        );
+
+        let mut body = Body::Str("héllo".to_owned());
+        truncate_body(&mut body, 2);
+        assert_eq!(body, Body::Str("h[TRUNCATED at 1 bytes]".to_owned()));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn upgrade_stale_content_lengths() -> Result<(), VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml")
+            .await?
+            .to_truncated_bodies(4);
+
+        cassette.requests[0].headers.insert(
+            "Content-Length".to_owned(),
+            vec!["10".to_owned()]
+        );
+        cassette.responses[0].headers.insert(
+            "content-length".to_owned(),
+            vec!["10".to_owned()]
+        );
+        cassette.responses[1].body = Body::File {
+            file: "body.bin".into(),
+            len: None,
+            sha256: None,
+        };
+        cassette.responses[1].headers.insert(
+            "content-length".to_owned(),
+            vec!["10".to_owned()]
+        );
+
+        cassette.upgrade_content_lengths();
+
+        let len = "My R[TRUNCATED at 4 bytes]".len().to_string();
+        assert_eq!(cassette.requests[0].headers["Content-Length"], vec![len]);
+
+        let len = "A Re[TRUNCATED at 4 bytes]".len().to_string();
+        assert_eq!(cassette.responses[0].headers["content-length"], vec![len]);
+
+        // Headers aren't added, and stored files aren't measured.
+        assert!(! cassette.requests[1].headers.contains_key("content-length"));
+        assert_eq!(cassette.responses[1].headers["content-length"], vec!["10"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_entries_by_path_prefix() {
+        let pair = |path: &str| (
+            VcrRequest {
+                method: Method::Get,
+                url: Url::parse("https://example.com").unwrap().join(path)
+                    .unwrap(),
+                headers: HashMap::new(),
+                body: Body::Str("".to_owned()),
+            },
+            VcrResponse {
+                status: surf::StatusCode::Ok,
+                headers: HashMap::new(),
+                body: Body::Str(path.to_owned()),
+                ..Default::default()
+            }
+        );
+
+        let cassette = VcrCassette::build_from_pairs(vec![
+            pair("/api/v1/users"),
+            pair("/api/v2/login"),
+            pair("/health"),
+            pair("/api/v2/users"),
+        ])
+            .with_metadata(CassetteMetadata {
+                tags: vec!["users".to_owned()],
+                ..CassetteMetadata::default()
+            })
+            .require_order(1, 3)
+            .require_order(0, 3);
+
+        let v2 = cassette.filter_by_path_prefix("/api/v2/");
+
+        assert_eq!(v2.len(), 2);
+        assert_eq!(v2.responses[0].body, Body::Str("/api/v2/login".to_owned()));
+        assert_eq!(v2.responses[1].body, Body::Str("/api/v2/users".to_owned()));
+
+        let meta = v2.metadata().unwrap();
+        assert_eq!(meta.tags, vec!["users"]);
+        assert_eq!(meta.order, vec![OrderConstraint { before: 0, after: 1 }]);
+
+        assert!(cassette.filter_by_path_prefix("/api/v3/").is_empty());
+        assert_eq!(cassette.len(), 4);
+    }
+
+    #[async_std::test]
+    async fn cassette_coverage() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let coverage = cassette.coverage();
+
+        assert_eq!(coverage.len(), cassette.len());
+        assert_eq!(coverage[0].0, cassette.requests[0].method);
+        assert_eq!(coverage[0].1, cassette.requests[0].url);
+
+        let vcr = VcrMiddleware::from_memory(cassette.clone().into_memory());
+        assert!(cassette.can_satisfy(&cassette.requests[0], &vcr));
+
+        let mut missing = cassette.requests[0].clone();
+        missing.url.set_path("/not-recorded");
+        assert!(! cassette.can_satisfy(&missing, &vcr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn index_requests_by_method_and_url() {
+        let pair = |method, path: &str| (
+            VcrRequest {
+                method,
+                url: Url::parse("https://example.com").unwrap().join(path)
+                    .unwrap(),
+                headers: HashMap::new(),
+                body: Body::Str("".to_owned()),
+            },
+            VcrResponse {
+                status: StatusCode::Ok,
+                headers: HashMap::new(),
+                body: Body::Str(path.to_owned()),
+                ..Default::default()
+            }
+        );
+
+        // 300 entries over 100 URLs; every third entry is a POST.
+        let pairs = (0..300).map(|i| {
+            let method = if i % 3 == 0 { Method::Post } else { Method::Get };
+            pair(method, &format!("/items/{}?page={}", i % 100, i))
+        });
+        let mut cassette = IndexedCassette::from(
+            VcrCassette::build_from_pairs(pairs)
+        );
+
+        let request = pair(Method::Get, "/items/7#details").0;
+        assert_eq!(cassette.candidates(&request), &[7, 107]);
+
+        let request = pair(Method::Post, "/items/7").0;
+        assert_eq!(cassette.candidates(&request), &[207]);
+
+        let request = pair(Method::Get, "/items/100").0;
+        assert!(cassette.candidates(&request).is_empty());
+
+        cassette.push(pair(Method::Get, "/items/7").0, pair(Method::Get, "").1);
+        assert_eq!(
+            cassette.candidates(&pair(Method::Get, "/items/7").0),
+            &[7, 107, 300]
+        );
+        assert_eq!(cassette.len(), 301);
+    }
+
+    #[test]
+    fn summarize_response_statuses() {
+        let status = |status| (
+            VcrRequest::new(
+                Method::Get,
+                Url::parse("https://example.com").unwrap()
+            ),
+            VcrResponse {
+                status,
+                headers: HashMap::new(),
+                body: Body::Str("".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        let cassette = VcrCassette::build_from_pairs(vec![
+            status(StatusCode::NotFound),
+            status(StatusCode::Ok),
+            status(StatusCode::ServiceUnavailable),
+            status(StatusCode::Ok),
+        ]);
+
+        assert_eq!(
+            cassette.unique_response_status_codes().into_iter()
+                .collect::<Vec<_>>(),
+            vec![200, 404, 503]
+        );
+        assert!(VcrCassette::default().unique_response_status_codes()
+            .is_empty());
+
+        assert_eq!(cassette.response_success_rate(), 0.5);
+        assert_eq!(VcrCassette::default().response_success_rate(), 1.0);
+    }
+
+    #[test]
+    fn find_entries_by_header_value() {
+        let flagged = |req_flag: Option<&str>, res_flag: Option<&str>| {
+            let mut req = VcrRequest::new(
+                Method::Get,
+                Url::parse("https://example.com").unwrap()
+            );
+            let mut res = VcrResponse::new(StatusCode::Ok);
+
+            if let Some(flag) = req_flag {
+                req = req.with_header("X-Feature-Flag", flag);
+            }
+            if let Some(flag) = res_flag {
+                res.headers.insert("X-FEATURE-FLAG".to_owned(), vec![
+                    "other".to_owned(),
+                    flag.to_owned(),
+                ]);
+            }
+
+            (req, res)
+        };
+
+        let cassette = VcrCassette::build_from_pairs(vec![
+            flagged(Some("enabled"), None),
+            flagged(None, None),
+            flagged(Some("disabled"), None),
+            flagged(None, Some("enabled")),
+            flagged(Some("Enabled"), None),
+        ]);
+
+        assert_eq!(
+            cassette.find_by_header_value("x-feature-flag", "enabled"),
+            vec![0, 3]
+        );
+        assert_eq!(
+            cassette.find_by_header_value("X-Feature-Flag", "disabled"),
+            vec![2]
+        );
+        assert!(cassette.find_by_header_value("X-Other", "enabled").is_empty());
+    }
+
+    #[async_std::test]
+    async fn list_distinct_endpoints() -> Result<(), VcrError> {
+        let mut cassette =
+            VcrCassette::load("test-sessions/simple.yml").await?;
+        let url = Url::parse("https://example.com").unwrap();
+
+        assert_eq!(cassette.endpoints(), vec![(Method::Get, url.clone())]);
+
+        let (mut req, res) = cassette.entry_at(0)
+            .map(|(req, res)| (req.clone(), res.clone()))
+            .unwrap();
+        req.method = Method::Post;
+        cassette.push(req.clone(), res.clone());
+
+        req.url.set_path("/other");
+        cassette.push(req, res);
+
+        assert_eq!(cassette.endpoints(), vec![
+            (Method::Get, url.clone()),
+            (Method::Post, url),
+            (Method::Post, Url::parse("https://example.com/other").unwrap()),
+        ]);
+        assert!(VcrCassette::default().endpoints().is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn find_request_by_url_and_method() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let url = Url::parse("https://example.com").unwrap();
+
+        assert_eq!(
+            cassette.request_by_url_and_method(&url, Method::Get),
+            cassette.request_at_index(0)
+        );
+        assert_eq!(cassette.request_by_url_and_method(&url, Method::Put), None);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn build_cassette_from_pairs() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let pairs = cassette.requests.iter().cloned()
+            .zip(cassette.responses.iter().cloned());
+        let first = pairs.clone().take(1);
+
+        assert_eq!(VcrCassette::build_from_pairs(pairs), cassette);
+
+        let chained = VcrCassette::build_from_pairs(first.chain(
+            cassette.entry_at(2).map(|(req, res)| (req.clone(), res.clone()))
+        ));
+
+        assert_eq!(chained.len(), 2);
+        assert_eq!(chained.request_at_index(1), cassette.request_at_index(2));
+
+        Ok(())
+    }
+}
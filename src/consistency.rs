@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use http_types::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use crate::{core::Session, RetryPolicy, VcrRequest, VcrResponse};
+
+
+/// A request that was recorded more than once in a session, with responses
+/// that differ in their status or body.
+///
+/// This is often a sign of changing server state, which makes replays depend
+/// on the order of the requests; see
+/// `VcrMiddleware::fail_on_inconsistent_recording`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InconsistentResponse {
+    pub method: Method,
+    pub url: Url,
+    /// The status of the response recorded first.
+    pub first_status: StatusCode,
+    /// The status of the differing response.
+    pub status: StatusCode,
+    /// The differences between the responses, such as
+    /// `status 200 -> 503; body 12 -> 0 bytes`.
+    pub summary: String,
+}
+
+impl fmt::Display for InconsistentResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} was recorded with different responses: {}",
+            self.method, self.url, self.summary)
+    }
+}
+
+// The outcome of a recorded request, kept for the rest of the session.
+#[derive(Debug)]
+pub(crate) struct RecordedOutcome {
+    pub(crate) request: VcrRequest,
+    status: StatusCode,
+    // The length and hash of the body, if it's in memory.
+    body: Option<(usize, String)>,
+}
+
+impl RecordedOutcome {
+    pub(crate) fn new(request: &VcrRequest, response: &VcrResponse) -> Self {
+        let body = response.body.bytes().map(|bytes| (
+            bytes.len(),
+            Sha256::digest(bytes).iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        ));
+
+        Self {
+            request: request.clone(),
+            status: response.status,
+            body,
+        }
+    }
+
+    // Compare the outcome to that of an earlier request; bodies that aren't
+    // in memory are not compared.
+    pub(crate) fn compare(&self, first: &RecordedOutcome)
+    -> Option<InconsistentResponse> {
+        let mut differences = vec![];
+
+        if self.status != first.status {
+            differences.push(format!(
+                "status {} -> {}",
+                first.status as u16,
+                self.status as u16
+            ));
+        }
+
+        if let (Some((first_len, first_hash)), Some((len, hash)))
+            = (&first.body, &self.body)
+        {
+            if hash != first_hash {
+                differences.push(format!(
+                    "body {} -> {} bytes",
+                    first_len,
+                    len
+                ));
+            }
+        }
+
+        if differences.is_empty() {
+            return None;
+        }
+
+        Some(InconsistentResponse {
+            method: self.request.method,
+            url: self.request.url.clone(),
+            first_status: first.status,
+            status: self.status,
+            summary: differences.join("; "),
+        })
+    }
+}
+
+impl Session {
+    // Remember the outcome of a request being recorded, and report whether
+    // an earlier matching request was recorded with a different response.
+    pub(crate) fn check_consistency(
+        &self,
+        request: &VcrRequest,
+        response: &VcrResponse
+    ) -> Option<InconsistentResponse> {
+        let outcome = RecordedOutcome::new(request, response);
+        let mut outcomes = self.state.0.outcomes.lock().unwrap();
+
+        // A retry that replaces the last recording is not compared to it.
+        if self.retry_policy == RetryPolicy::KeepLast
+            && outcomes.last().is_some_and(|o| &o.request == request)
+        {
+            outcomes.pop();
+        }
+
+        let inconsistency = outcomes.iter()
+            .find(|first| self.request_matches(&first.request, request))
+            .and_then(|first| outcome.compare(first));
+
+        if let Some(inconsistency) = &inconsistency {
+            log::warn!("{}", inconsistency);
+
+            self.state.0.inconsistencies.lock().unwrap()
+                .push(inconsistency.clone());
+        }
+
+        if inconsistency.is_none() || ! self.fail_on_inconsistent {
+            outcomes.push(outcome);
+        }
+
+        inconsistency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::Body;
+
+    fn outcome(status: StatusCode, body: &str) -> RecordedOutcome {
+        let request = VcrRequest::new(
+            Method::Get,
+            Url::parse("https://example.com/state").unwrap()
+        );
+
+        let response = VcrResponse {
+            status,
+            headers: HashMap::new(),
+            body: Body::Str(body.to_owned()),
+            ..Default::default()
+        };
+
+        RecordedOutcome::new(&request, &response)
+    }
+
+    #[test]
+    fn summarize_differences() {
+        let first = outcome(StatusCode::Ok, "ready");
+
+        assert_eq!(outcome(StatusCode::Ok, "ready").compare(&first), None);
+
+        let changed = outcome(StatusCode::ServiceUnavailable, "")
+            .compare(&first)
+            .unwrap();
+
+        assert_eq!(changed.first_status, StatusCode::Ok);
+        assert_eq!(changed.status, StatusCode::ServiceUnavailable);
+        assert_eq!(changed.summary, "status 200 -> 503; body 5 -> 0 bytes");
+        assert_eq!(
+            changed.to_string(),
+            "GET https://example.com/state was recorded with different \
+            responses: status 200 -> 503; body 5 -> 0 bytes"
+        );
+
+        let body_only = outcome(StatusCode::Ok, "gone!").compare(&first);
+        assert_eq!(body_only.unwrap().summary, "body 5 -> 5 bytes");
+    }
+}
@@ -0,0 +1,254 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use jsonschema::JSONSchema;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use surf::Url;
+
+use crate::{Body, VcrError};
+
+
+// The errors found in each response body checked against each schema, keyed
+// by the digest of the schema and body.
+static CHECKED: Lazy<Mutex<HashMap<String, Vec<String>>>> =
+    Lazy::new(Default::default);
+
+/// A JSON Schema that the bodies of replayed responses to matching URLs must
+/// satisfy, so a cassette can double as a consumer-driven contract; see
+/// `VcrMiddleware::with_contract`.
+///
+/// The URL pattern is compared to the path of the recorded request, segment
+/// by segment: a `*` segment matches any single segment, and a final `**`
+/// segment matches any remaining segments.
+///
+/// Each response body is checked against each schema once per process, so
+/// repeated replays are cheap. Bodies stored in separate files are not
+/// checked.
+///
+/// Contracts require the `contracts` feature.
+///
+/// ```
+/// # async fn create() -> Result<(), surf_vcr::VcrError> {
+/// use serde_json::json;
+/// use surf_vcr::{Contract, VcrMiddleware, VcrMode};
+///
+/// let user = Contract::new("/v1/users/*", &json!({
+///     "type": "object",
+///     "required": ["id", "name"],
+/// }))?;
+///
+/// let vcr = VcrMiddleware::new(
+///     VcrMode::Replay,
+///     "test-sessions/simple.yml"
+/// ).await?
+///     .with_contract(user);
+/// # Ok(()) }
+/// ```
+#[derive(Clone)]
+pub struct Contract {
+    pattern: String,
+    schema: Value,
+    compiled: Arc<JSONSchema>,
+    digest: String,
+}
+
+impl Contract {
+    /// Create a contract requiring the bodies of responses to requests whose
+    /// path matches `url_pattern` to satisfy `schema`.
+    ///
+    /// Fails with [VcrError::InvalidSchema] if `schema` is not a valid JSON
+    /// Schema.
+    pub fn new(url_pattern: &str, schema: &Value) -> Result<Self, VcrError> {
+        let compiled = JSONSchema::compile(schema)
+            .map_err(|e| VcrError::InvalidSchema(e.to_string()))?;
+
+        Ok(Self {
+            pattern: url_pattern.to_owned(),
+            schema: schema.clone(),
+            compiled: Arc::new(compiled),
+            digest: hex_digest(schema.to_string().as_bytes()),
+        })
+    }
+
+    /// Check the body of the recorded response to a request for `url`, if
+    /// the contract applies to it.
+    pub(crate) fn check(&self, url: &Url, body: &Body)
+    -> Result<(), VcrError> {
+        let bytes = match body.bytes() {
+            Some(bytes) if path_matches(&self.pattern, url.path()) => bytes,
+            _ => return Ok(()),
+        };
+
+        let key = format!("{}:{}", self.digest, hex_digest(bytes));
+        let cached = CHECKED.lock().unwrap().get(&key).cloned();
+
+        let errors = match cached {
+            Some(errors) => errors,
+            None => {
+                let errors = self.validate(bytes);
+                CHECKED.lock().unwrap().insert(key, errors.clone());
+                errors
+            },
+        };
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(VcrError::ContractViolation { url: url.clone(), errors })
+        }
+    }
+
+    fn validate(&self, body: &[u8]) -> Vec<String> {
+        #[cfg(test)]
+        tests::count_validation(&self.digest);
+
+        let instance = match serde_json::from_slice::<Value>(body) {
+            Ok(instance) => instance,
+            Err(e) => return vec![format!("The body is not JSON: {}", e)],
+        };
+
+        let errors = match self.compiled.validate(&instance) {
+            Ok(()) => vec![],
+            Err(errors) => errors.map(|e| e.to_string()).collect(),
+        };
+
+        errors
+    }
+}
+
+impl PartialEq for Contract {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.schema == other.schema
+    }
+}
+
+impl Eq for Contract {}
+
+impl fmt::Debug for Contract {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Contract")
+            .field("pattern", &self.pattern)
+            .field("schema", &self.schema)
+            .finish()
+    }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.trim_matches('/').split('/');
+    let mut path = path.trim_matches('/').split('/');
+
+    loop {
+        match (pattern.next(), path.next()) {
+            (Some("**"), _) => return true,
+            (Some(p), Some(segment)) if p == "*" || p == segment => {},
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The number of times bodies were validated against each schema.
+    static VALIDATIONS: Lazy<Mutex<HashMap<String, usize>>> =
+        Lazy::new(Default::default);
+
+    pub(super) fn count_validation(digest: &str) {
+        *VALIDATIONS.lock().unwrap().entry(digest.to_owned()).or_default() += 1;
+    }
+
+    fn user_contract(required: &str) -> Contract {
+        Contract::new("/v1/users/*", &json!({
+            "type": "object",
+            "required": [required],
+        })).unwrap()
+    }
+
+    fn url(path: &str) -> Url {
+        Url::parse("https://example.com").unwrap().join(path).unwrap()
+    }
+
+    #[test]
+    fn match_url_patterns() {
+        assert!(path_matches("/v1/users/*", "/v1/users/12"));
+        assert!(path_matches("/v1/users/*", "/v1/users/12/"));
+        assert!(! path_matches("/v1/users/*", "/v1/users/12/posts"));
+        assert!(! path_matches("/v1/users/*", "/v1/users"));
+        assert!(path_matches("/v1/**", "/v1/users/12/posts"));
+        assert!(path_matches("/", "/"));
+    }
+
+    #[test]
+    fn satisfied_contract() {
+        let contract = user_contract("name");
+        let body = Body::Str(r#"{"id": 1, "name": "Ann"}"#.to_owned());
+
+        assert!(contract.check(&url("/v1/users/1"), &body).is_ok());
+
+        // The contract doesn't apply to other URLs.
+        let other = Body::Str("not JSON".to_owned());
+        assert!(contract.check(&url("/v1/posts/1"), &other).is_ok());
+    }
+
+    #[test]
+    fn violated_contract() {
+        let contract = user_contract("email");
+        let body = Body::Str(r#"{"id": 1, "name": "Ann"}"#.to_owned());
+
+        match contract.check(&url("/v1/users/1"), &body) {
+            Err(VcrError::ContractViolation { url, errors }) => {
+                assert_eq!(url.path(), "/v1/users/1");
+                assert_eq!(errors.len(), 1);
+                assert!(errors[0].contains("email"));
+            },
+            res => panic!("Expected a contract violation; got {:?}", res),
+        }
+
+        assert!(matches!(
+            contract.check(&url("/v1/users/1"), &Body::Str("{".to_owned())),
+            Err(VcrError::ContractViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn cache_checked_bodies() {
+        // A schema no other test uses, so its validations are counted here.
+        let contract = user_contract("cache_checked_bodies");
+        let body = Body::Str(r#"{"id": 1}"#.to_owned());
+
+        for _ in 0..3 {
+            assert!(contract.check(&url("/v1/users/1"), &body).is_err());
+        }
+
+        let other = Body::Str(r#"{"cache_checked_bodies": 2}"#.to_owned());
+        assert!(contract.check(&url("/v1/users/2"), &other).is_ok());
+
+        assert_eq!(VALIDATIONS.lock().unwrap()[&contract.digest], 2);
+    }
+
+    #[test]
+    fn invalid_schema() {
+        assert!(matches!(
+            Contract::new("/", &json!({"type": 12})),
+            Err(VcrError::InvalidSchema(_))
+        ));
+    }
+}
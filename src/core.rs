@@ -0,0 +1,1566 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The HTTP client-agnostic core of Surf-vcr: cassettes, request matching,
+//! recording hooks, and storage, without any Surf types.
+//!
+//! `VcrMiddleware` adapts the core to Surf. To record and replay sessions of
+//! another HTTP client, convert its requests and responses to [VcrRequest]
+//! and [VcrResponse] and drive a [Session]. The core doesn't need the default
+//! `surf` feature, which only `VcrMiddleware` uses:
+//!
+//! ```no_run
+//! # async fn send(req: &surf_vcr::core::VcrRequest)
+//! # -> surf_vcr::core::VcrResponse { unimplemented!() }
+//! # async fn run() -> Result<(), surf_vcr::core::Error> {
+//! use surf_vcr::core::{Method, Session, Url, VcrMode, VcrRequest};
+//!
+//! let session = Session::new(VcrMode::Once, "sessions/my-session.yml")
+//!     .await?;
+//!
+//! let request = VcrRequest::new(
+//!     Method::Get,
+//!     Url::parse("https://example.com/items").unwrap()
+//! );
+//!
+//! // A request that wasn't recorded fails when replaying, and is sent to the
+//! // server when recording.
+//! let response = match session.replay(request.clone()).await? {
+//!     Some(response) => response,
+//!     None => {
+//!         let response = send(&request).await;
+//!         session.record(request, response.clone()).await?;
+//!         response
+//!     },
+//! };
+//!
+//! session.finish().await;
+//! # Ok(()) }
+//! ```
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::{prelude::*, fs, sync::RwLock};
+use futures_timer::Delay;
+
+use crate::{
+    cassette::{self, IndexedCassette},
+    consistency,
+    consistent_framing,
+    encoding,
+    has_recording,
+    rewrite,
+    secrets,
+    vary_matches,
+    without_fragment,
+    ByPtr,
+    InterceptFn,
+    InterceptState,
+    InteractionTiming,
+    RecordSink,
+    ReplayStrategy,
+    RepeatPolicy,
+    RequestHookFn,
+    RequestMatcherFn,
+    ResponseHookFn,
+    RetryPolicy,
+    SerdeWrapper,
+    VcrCassette,
+    CASSETTES,
+    CONDITIONAL_HEADERS,
+    CURSORS,
+    DEFAULT_STREAM_THRESHOLD,
+    REPLACING,
+    SIDECAR_COUNT,
+};
+#[cfg(feature = "contracts")]
+use crate::Contract;
+
+pub use http_types::{Method, StatusCode, Url, Version};
+
+pub use crate::{
+    AnonymizeConfig,
+    Body,
+    BodyMatcher,
+    InconsistentResponse,
+    MatchStrategy,
+    MatchTrace,
+    MemoryCassette,
+    SessionReport,
+    SuspectedSecret,
+    VcrCassette as Cassette,
+    VcrFormat,
+    VcrMatchOptions,
+    VcrMode,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+/// A recorded request and its response.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Interaction {
+    pub request: VcrRequest,
+    pub response: VcrResponse,
+}
+
+/// An error recording or replaying a [Session], and the error the Surf
+/// middleware returns as `VcrError`.
+#[derive(Debug)]
+pub enum Error {
+    File(io::Error),
+    Parse(serde_yaml::Error),
+    /// A JSON cassette could not be parsed or written.
+    Json(serde_json::Error),
+    /// No request recorded in the cassette, which has `recorded`
+    /// interactions, matches the request with the method and URL. The
+    /// [MatchTrace] lists the recorded requests that were searched and the
+    /// closest match.
+    NoMatchingRequest {
+        method: Method,
+        url: Url,
+        recorded: usize,
+        trace: Option<MatchTrace>,
+    },
+    /// A response references a shared body that is not in the cassette.
+    MissingBody(String),
+    /// A request or response references a shared set of headers that is not
+    /// in the cassette.
+    MissingHeaders(usize),
+    /// The format of the cassette file could not be determined.
+    Format(PathBuf),
+    /// A response from the URL was not recorded because it appears to contain
+    /// a secret.
+    SuspectedSecret(Url, SuspectedSecret),
+    /// The request was sent after the session was finished.
+    SessionFinished,
+    /// A recorded response body was decoded by a codec that has no encoding
+    /// hook.
+    UnknownCodec(String),
+    /// A request with a method that changes data on the server was not sent
+    /// while recording (see [UnsafePolicy](crate::UnsafePolicy)).
+    UnsafeMethodBlocked(Method, Url),
+    /// A string could not be parsed as a [VcrMode].
+    InvalidMode(String),
+    /// The interaction at the first position was replayed before the one at
+    /// the second, which must be replayed first (see
+    /// [Cassette::require_order]).
+    OrderViolation(usize, usize),
+    /// A schema given to `Contract::new` is not a valid JSON Schema.
+    InvalidSchema(String),
+    /// The recorded response to the URL does not satisfy the contract for
+    /// it; see `VcrMiddleware::with_contract`.
+    ContractViolation { url: Url, errors: Vec<String> },
+    /// Cassettes in the archive cannot be recorded to; see
+    /// `VcrMiddleware::from_archive`.
+    ReadOnlyArchive(PathBuf),
+    /// More requests were sent during a replay than the limit allows (see
+    /// [VcrMiddleware::max_requests](crate::VcrMiddleware::max_requests)).
+    RequestLimitExceeded(usize),
+    /// The cassette a session replays is not loaded.
+    NotLoaded(PathBuf),
+    /// A request was recorded with a response that differs from the one
+    /// recorded earlier in the session (see
+    /// [VcrMiddleware::fail_on_inconsistent_recording](
+    /// crate::VcrMiddleware::fail_on_inconsistent_recording)).
+    InconsistentRecording(InconsistentResponse),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(e) => e.fmt(f),
+            Self::Parse(e) => e.fmt(f),
+            Self::Json(e) => e.fmt(f),
+            Self::NoMatchingRequest { method, url, recorded, trace } => {
+                write!(f, "No recorded request matches {} {} among the {} \
+                    loaded from the cassette", method, url, recorded)?;
+
+                match trace {
+                    Some(trace) => write!(f, "\n{}", trace),
+                    None => Ok(()),
+                }
+            },
+            Self::MissingBody(hash) =>
+                write!(f, "Shared body not found in cassette: {}", hash),
+            Self::MissingHeaders(index) =>
+                write!(f, "Shared headers not found in cassette: #{}", index),
+            Self::Format(path) =>
+                write!(f, "Unknown cassette format: {}", path.display()),
+            Self::SuspectedSecret(url, secret) =>
+                write!(f, "Refusing to record {}: {}", url, secret),
+            Self::SessionFinished =>
+                write!(f, "Request sent after the session was finished"),
+            Self::UnknownCodec(codec) =>
+                write!(f, "No encoder registered for body codec: {}", codec),
+            Self::UnsafeMethodBlocked(method, url) =>
+                write!(f, "Refusing to send {} {} while recording",
+                    method, url),
+            Self::InvalidMode(mode) => write!(f, "Unknown VCR mode: {}", mode),
+            Self::OrderViolation(pos, before) => write!(f,
+                "Interaction #{} replayed before #{}, which must come first",
+                pos, before),
+            Self::InvalidSchema(e) => write!(f, "Invalid JSON Schema: {}", e),
+            Self::ContractViolation { url, errors } => write!(f,
+                "The response to {} violates its contract:\n  {}",
+                url, errors.join("\n  ")),
+            Self::ReadOnlyArchive(path) => write!(f,
+                "Cannot record to a cassette in archive {}", path.display()),
+            Self::RequestLimitExceeded(max) => write!(f,
+                "More than {} requests were sent during the replay", max),
+            Self::NotLoaded(path) =>
+                write!(f, "Cassette not loaded: {}", path.display()),
+            Self::InconsistentRecording(inconsistency) =>
+                write!(f, "Refusing to record: {}", inconsistency),
+        }
+    }
+}
+
+impl Error {
+    pub(crate) fn no_match(
+        request: &VcrRequest,
+        recorded: usize,
+        trace: Option<MatchTrace>
+    ) -> Self {
+        Self::NoMatchingRequest {
+            method: request.method,
+            url: request.url.clone(),
+            recorded,
+            trace,
+        }
+    }
+
+    /// The status of the `surf::Error` the middleware fails a request with
+    /// for this error: `404 Not Found` if no recorded request matched, and
+    /// `500 Internal Server Error` otherwise.
+    ///
+    /// Surf converts any error into a `surf::Error` with a 500 status, so
+    /// misses are converted with `surf::Error::new(err.status(), err)`.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NoMatchingRequest { .. } => StatusCode::NotFound,
+            _ => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Self::File(e) }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self { Self::Parse(e) }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self { Self::Json(e) }
+}
+
+/// A recording or replay session with a cassette, driven directly by an
+/// HTTP client adapter.
+///
+/// The session is shared by clones; cassettes are shared with other sessions
+/// using the same file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Session {
+    pub(crate) mode: VcrMode,
+    pub(crate) file: PathBuf,
+    pub(crate) format: VcrFormat,
+    pub(crate) stream_bodies: bool,
+    pub(crate) stream_threshold: u64,
+    #[cfg(feature = "contracts")]
+    pub(crate) contracts: Vec<Contract>,
+    pub(crate) anonymize: Option<AnonymizeConfig>,
+    pub(crate) request_hooks: Vec<ByPtr<RequestHookFn>>,
+    pub(crate) response_hooks: Vec<ByPtr<ResponseHookFn>>,
+    pub(crate) correlation_header: Option<String>,
+    pub(crate) body_matchers: Vec<(String, ByPtr<dyn BodyMatcher>)>,
+    pub(crate) sink: Option<ByPtr<RecordSink>>,
+    pub(crate) fail_on_secrets: bool,
+    pub(crate) secret_allowlist: Vec<String>,
+    pub(crate) fail_on_inconsistent: bool,
+    pub(crate) finish_timeout: Duration,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) track_usage: bool,
+    pub(crate) dedup_bodies: bool,
+    pub(crate) strict: bool,
+    pub(crate) failure_artifact_dir: Option<PathBuf>,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) memory: Option<MemoryCassette>,
+    pub(crate) redacted_query_params: Vec<String>,
+    pub(crate) replay_interceptor: Option<ByPtr<InterceptFn>>,
+    pub(crate) repeat_policy: RepeatPolicy,
+    pub(crate) replay_strategy: ReplayStrategy,
+    pub(crate) inline_redirects: bool,
+    pub(crate) diagnostic_body_limit: Option<usize>,
+    pub(crate) match_options: VcrMatchOptions,
+    pub(crate) conditional_headers: Option<bool>,
+    pub(crate) request_matcher: Option<ByPtr<RequestMatcherFn>>,
+    pub(crate) state: SessionState,
+}
+
+// Session state, shared by clones of a session.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SessionState(pub(crate) Arc<SessionStateInner>);
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionStateInner {
+    // Maps the correlation IDs sent by the client to the recorded IDs.
+    pub(crate) correlations: Mutex<HashMap<String, String>>,
+    // The number of requests currently being handled.
+    pub(crate) in_flight: AtomicUsize,
+    // The number of requests accepted.
+    pub(crate) handled: AtomicUsize,
+    pub(crate) finished: AtomicBool,
+    // The last interaction recorded, used to collapse retries.
+    pub(crate) last_recorded: Mutex<Option<LastRecorded>>,
+    // The hashes of the bodies the session has written to shared body
+    // documents.
+    pub(crate) shared_bodies: Mutex<HashSet<String>>,
+    // The positions of the replayed interactions.
+    pub(crate) played: Mutex<BTreeSet<usize>>,
+    // Held while an interaction is chosen and marked as replayed, so that
+    // concurrent requests don't replay the same interaction.
+    pub(crate) selecting: Mutex<()>,
+    // The position of the next interaction to replay sequentially, shared by
+    // the sessions replaying the same cassette file.
+    pub(crate) cursor: Arc<AtomicUsize>,
+    // Shared by calls to the replay interceptor.
+    pub(crate) intercept_state: Mutex<InterceptState>,
+    // The title of the next interaction to be recorded.
+    pub(crate) next_title: Mutex<Option<String>>,
+    // The number of matched replays that may have been failed.
+    pub(crate) flaky_draws: AtomicU64,
+    // The start of the session, from which interactions are timed.
+    pub(crate) clock: SessionClock,
+    // The sequence number of the next interaction to be recorded.
+    pub(crate) next_sequence: AtomicU64,
+    // The number of requests looked up in the cassette.
+    pub(crate) replay_requests: AtomicUsize,
+    // The outcomes of the requests recorded in the session.
+    pub(crate) outcomes: Mutex<Vec<consistency::RecordedOutcome>>,
+    // Requests recorded with differing responses.
+    pub(crate) inconsistencies: Mutex<Vec<InconsistentResponse>>,
+    // The number of failure artifacts written.
+    pub(crate) failure_artifacts: AtomicUsize,
+    // The targets of the replayed redirects, and the positions of the
+    // interactions that redirected to them.
+    pub(crate) redirects: Mutex<HashMap<Url, usize>>,
+}
+
+// A recorded interaction that a retry of its request may replace.
+#[derive(Debug)]
+pub(crate) struct LastRecorded {
+    pub(crate) request: VcrRequest,
+    // The offsets in the cassette file where the interaction starts and where
+    // the file ended after it.
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    // The body first shared by the interaction.
+    pub(crate) shared_body: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SessionClock(Instant);
+
+impl Default for SessionClock {
+    fn default() -> Self { Self(Instant::now()) }
+}
+
+impl SessionClock {
+    pub(crate) fn elapsed_millis(&self) -> u64 {
+        self.0.elapsed().as_millis() as u64
+    }
+}
+
+impl PartialEq for SessionState {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+}
+
+impl Eq for SessionState {}
+
+// Counts a request as in flight until dropped.
+pub(crate) struct InFlight(Arc<SessionStateInner>);
+
+impl InFlight {
+    pub(crate) fn new(state: &SessionState) -> Self {
+        state.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(state.0.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AsRef<Session> for Session {
+    fn as_ref(&self) -> &Session { self }
+}
+
+impl Session {
+    /// Open a session with the cassette at `path`. `Once` and `All` are
+    /// resolved to record or replay, and `None` to replay; see
+    /// [mode](Self::mode).
+    pub async fn new<P>(mode: VcrMode, path: P) -> Result<Self, Error>
+        where P: Into<PathBuf>,
+    {
+        Self::open(mode, path.into(), None).await
+    }
+
+    /// Open a session replaying a cassette in memory (see
+    /// [Cassette::into_memory]).
+    pub fn from_memory(cassette: MemoryCassette) -> Self {
+        Self {
+            memory: Some(cassette),
+            ..Self::with_options(VcrMode::Replay, PathBuf::new())
+        }
+    }
+
+    /// Choose which fields of a request are compared when searching for a
+    /// recorded request.
+    pub fn with_match_options(mut self, opts: VcrMatchOptions) -> Self {
+        self.match_options = opts;
+        self
+    }
+
+    /// Compare request bodies of the content type with a custom matcher.
+    pub fn with_body_matcher<C, M>(mut self, content_type: C, matcher: M)
+    -> Self
+        where C: Into<String>,
+              M: BodyMatcher + 'static,
+    {
+        self.body_matchers.push((
+            content_type.into().to_ascii_lowercase(),
+            ByPtr(Arc::new(matcher))
+        ));
+        self
+    }
+
+    /// Anonymize requests and responses before they're recorded or matched.
+    pub fn with_anonymizer(mut self, config: AnonymizeConfig) -> Self {
+        self.anonymize = Some(config);
+        self
+    }
+
+    /// Modify each request before it's recorded or matched.
+    pub fn with_modify_request<F>(mut self, hook: F) -> Self
+        where F: Fn(&mut VcrRequest) + Send + Sync + 'static,
+    {
+        self.request_hooks.push(ByPtr(Arc::new(hook)));
+        self
+    }
+
+    /// Modify each response before it's recorded.
+    pub fn with_modify_response<F>(mut self, hook: F) -> Self
+        where F: Fn(&mut VcrResponse) + Send + Sync + 'static,
+    {
+        self.response_hooks.push(ByPtr(Arc::new(hook)));
+        self
+    }
+
+    /// The mode the session resolved to; `Once` and `All` record or replay,
+    /// and `None` replays.
+    pub fn mode(&self) -> VcrMode { self.mode }
+
+    /// Search the cassette for the request and return the recorded response.
+    ///
+    /// Returns `None` if the request should be sent to the server instead:
+    /// when recording, or when no recorded request matches in `NewEpisodes`
+    /// mode. Record the response with [record](Self::record) in `Record` and
+    /// `NewEpisodes` modes.
+    ///
+    /// In `Replay` mode, a request that matches no recorded request fails
+    /// with [Error::NoMatchingRequest].
+    pub async fn replay(&self, request: VcrRequest)
+    -> Result<Option<VcrResponse>, Error> {
+        let _in_flight = self.accept()?;
+
+        if ! matches!(self.mode, VcrMode::Replay | VcrMode::NewEpisodes) {
+            return Ok(None);
+        }
+
+        self.count_replay()?;
+
+        let request = self.prepare_request(request);
+
+        let replay = |cassette: Option<&IndexedCassette>|
+        -> Result<Option<VcrResponse>, Error> {
+            let _selecting = self.state.0.selecting.lock().unwrap();
+
+            let pos = match cassette {
+                Some(cassette) => self.select_interaction(cassette, &request)?
+                    .map(|pos| (cassette, pos)),
+                None => None,
+            };
+
+            match pos {
+                Some((cassette, pos)) => Ok(Some(
+                    self.take_final_response(cassette, &request, pos).1
+                        .into_owned()
+                )),
+                None if self.mode == VcrMode::Replay => Err(Error::no_match(
+                    &request,
+                    cassette.map_or(0, |c| c.len()),
+                    None
+                )),
+                None => Ok(None),
+            }
+        };
+
+        if let Some(memory) = &self.memory {
+            return replay(Some(&memory.0));
+        }
+
+        let cassettes = CASSETTES.get().unwrap().read().await;
+        let session = cassettes[&self.file].read().await;
+
+        match session.as_ref() {
+            Some(cassette) => replay(Some(cassette)),
+            None => Err(Error::NotLoaded(self.file.clone())),
+        }
+    }
+
+    /// Record a request and the response the server sent.
+    ///
+    /// Nothing is recorded when the session is not recording; see
+    /// [replay](Self::replay).
+    pub async fn record(&self, request: VcrRequest, response: VcrResponse)
+    -> Result<(), Error> {
+        let _in_flight = self.accept()?;
+
+        if ! matches!(self.mode, VcrMode::Record | VcrMode::NewEpisodes) {
+            return Ok(());
+        }
+
+        let started_at = self.state.0.clock.elapsed_millis();
+        let request = self.prepare_request(request);
+        let response = self.prepare_response(
+            &request,
+            response,
+            None,
+            started_at
+        )?;
+        let retried = self.retried(&request);
+
+        Ok(self.write_interaction(request, response, retried).await?)
+    }
+
+    /// The interactions in the session's cassette, in the order they were
+    /// recorded.
+    ///
+    /// When recording, the cassette is read from its file, so this includes
+    /// the interactions recorded so far; nothing is returned in `Passthrough`
+    /// mode or when recording to a writer.
+    pub async fn interactions(&self) -> Vec<Interaction> {
+        let pairs = |cassette: &VcrCassette| {
+            cassette.requests.iter().cloned()
+                .zip(cassette.responses.iter().cloned())
+                .map(|(request, response)| Interaction { request, response })
+                .collect()
+        };
+
+        if let Some(memory) = &self.memory {
+            return pairs(&memory.0);
+        }
+
+        match self.mode {
+            VcrMode::Replay | VcrMode::NewEpisodes => {},
+            VcrMode::Record if self.sink.is_none() => {
+                if ! has_recording(&self.file) {
+                    return vec![];
+                }
+
+                return match VcrCassette::load_as(&self.file, self.format)
+                    .await
+                {
+                    Ok(cassette) => pairs(&cassette),
+                    Err(e) => {
+                        log::warn!(
+                            "Failed to read {}: {}",
+                            self.file.display(),
+                            e
+                        );
+                        vec![]
+                    },
+                };
+            },
+            _ => return vec![],
+        }
+
+        let cassettes = match CASSETTES.get() {
+            Some(cassettes) => cassettes.read().await,
+            None => return vec![],
+        };
+
+        match cassettes.get(&self.file) {
+            Some(session) =>
+                session.read().await.as_ref()
+                    .map_or(vec![], |cassette| pairs(cassette)),
+            None => vec![],
+        }
+    }
+
+    /// Panic if any interaction in the cassette has not been replayed during
+    /// the session, listing the method and URL of each. Nothing is checked
+    /// when not replaying.
+    pub async fn assert_all_played(&self) {
+        let unplayed = self.unplayed().await;
+
+        if ! unplayed.is_empty() {
+            panic!(
+                "Recorded interactions were not replayed:\n{}",
+                unplayed.join("\n")
+            );
+        }
+    }
+
+    /// Stop accepting requests, wait for those in flight to complete, and
+    /// report on the session. Requests made after the session is finished
+    /// fail with [Error::SessionFinished].
+    pub async fn finish(&self) -> SessionReport {
+        let state = &self.state.0;
+        state.finished.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + self.finish_timeout;
+
+        while state.in_flight.load(Ordering::SeqCst) > 0
+            && Instant::now() < deadline
+        {
+            Delay::new(Duration::from_millis(10)).await;
+        }
+
+        if self.strict {
+            let unplayed = self.unplayed().await;
+
+            if ! unplayed.is_empty() {
+                self.write_unplayed(unplayed).await;
+            }
+
+            self.assert_all_played().await;
+        }
+
+        if self.track_usage && self.mode == VcrMode::Replay {
+            let played = std::mem::take(&mut *state.played.lock().unwrap());
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+
+            if ! played.is_empty() {
+                let written = cassette::write_usage(&self.file, &played, now)
+                    .await;
+
+                if let Err(e) = written {
+                    log::warn!(
+                        "Failed to record replays in {}: {}",
+                        self.file.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        SessionReport {
+            requests: state.handled.load(Ordering::SeqCst),
+            unfinished: state.in_flight.load(Ordering::SeqCst),
+            inconsistent: state.inconsistencies.lock().unwrap().clone(),
+        }
+    }
+}
+
+// The steps of recording and replaying that don't depend on the HTTP client.
+impl Session {
+    // Open a session with the cassette at `recording`, loading the cassette
+    // to replay it.
+    pub(crate) async fn open(
+        mode: VcrMode,
+        recording: PathBuf,
+        format: Option<VcrFormat>
+    ) -> Result<Self, Error> {
+        let mut state = SessionState::default();
+
+        if mode == VcrMode::All {
+            // Ignore error; we only initialize once.
+            let _ = CASSETTES.set(RwLock::new(HashMap::new()));
+
+            // No interactions are written while the cassette is removed.
+            let _cassettes = CASSETTES.get().unwrap().write().await;
+
+            let replace = {
+                let mut replacing = REPLACING.get_or_init(Default::default)
+                    .lock().unwrap();
+                let sessions = replacing.entry(recording.clone())
+                    .or_default();
+
+                sessions.retain(|session| session.upgrade()
+                    .is_some_and(|s| ! s.finished.load(Ordering::SeqCst)));
+                sessions.push(Arc::downgrade(&state.0));
+                sessions.len() == 1
+            };
+
+            if replace {
+                remove_recording(&recording).await?;
+            }
+        }
+
+        // The mode is chosen once, so a cassette that's recorded during the
+        // session doesn't change it.
+        let mode = mode.resolve(has_recording(&recording));
+
+        match mode {
+            VcrMode::Replay | VcrMode::NewEpisodes => {
+                // Ignore error; we only initialize once.
+                let _ = CASSETTES.set(RwLock::new(HashMap::new()));
+
+                let mut cassettes = CASSETTES.get().unwrap().write().await;
+
+                // Sessions recording to the same file must share its lock, so
+                // a loaded cassette is stored in place.
+                let entry = cassettes.entry(recording.clone())
+                    .or_insert_with(|| RwLock::new(None));
+                let mut session = entry.write().await;
+                let loaded = session.is_none();
+
+                if loaded {
+                    *session = match mode {
+                        VcrMode::NewEpisodes if ! has_recording(&recording) =>
+                            Some(IndexedCassette::default()),
+                        _ => Some(match format {
+                            Some(format) =>
+                                VcrCassette::load_as(&recording, format).await?,
+                            None => VcrCassette::load(&recording).await?,
+                        }.into()),
+                    };
+                }
+
+                let mut cursors = CURSORS.get_or_init(Default::default).lock()
+                    .unwrap();
+
+                if loaded {
+                    cursors.remove(&recording);
+                }
+
+                // The state isn't shared with other sessions in these modes.
+                state = SessionState(Arc::new(SessionStateInner {
+                    cursor: cursors.entry(recording.clone()).or_default()
+                        .clone(),
+                    ..Default::default()
+                }));
+            },
+            VcrMode::Record => {
+                // Ignore error; we only initialize once.
+                let _ = CASSETTES.set(RwLock::new(HashMap::new()));
+
+                if let Some(dir) = recording.parent() {
+                    fs::create_dir_all(dir).await?;
+                }
+
+                // Sessions recording to the same file must share its lock so
+                // their writes are serialized. A cassette loaded to replay it
+                // stays loaded, and its new interactions are added to it.
+                let mut recorders = CASSETTES.get().unwrap().write().await;
+                recorders.entry(recording.clone())
+                    .or_insert_with(|| RwLock::new(None));
+            },
+            // We never touch the cassette.
+            _ => {},
+        }
+
+        let format = format.unwrap_or_else(|| {
+            VcrCassette::detect_format(&recording).unwrap_or(VcrFormat::Yaml)
+        });
+
+        Ok(Self {
+            format,
+            state,
+            ..Self::with_options(mode, recording)
+        })
+    }
+
+    // A session with every option at its default.
+    pub(crate) fn with_options(mode: VcrMode, file: PathBuf) -> Self {
+        Self {
+            mode,
+            file,
+            format: VcrFormat::Yaml,
+            stream_bodies: false,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            #[cfg(feature = "contracts")]
+            contracts: vec![],
+            anonymize: None,
+            request_hooks: vec![],
+            response_hooks: vec![],
+            correlation_header: None,
+            body_matchers: vec![],
+            sink: None,
+            fail_on_secrets: false,
+            secret_allowlist: vec![],
+            fail_on_inconsistent: false,
+            finish_timeout: Duration::from_secs(5),
+            retry_policy: RetryPolicy::KeepAll,
+            track_usage: false,
+            dedup_bodies: false,
+            strict: false,
+            failure_artifact_dir: None,
+            max_requests: None,
+            memory: None,
+            redacted_query_params: vec![],
+            replay_interceptor: None,
+            repeat_policy: RepeatPolicy::RepeatLast,
+            replay_strategy: ReplayStrategy::FindFirst,
+            inline_redirects: false,
+            diagnostic_body_limit: None,
+            match_options: VcrMatchOptions::default(),
+            conditional_headers: None,
+            request_matcher: None,
+            state: SessionState::default(),
+        }
+    }
+
+    // Count a request, unless the session is finished.
+    pub(crate) fn accept(&self) -> Result<InFlight, Error> {
+        // Count the request before checking whether the session is finished,
+        // so that finish() either waits for it or it is rejected.
+        let in_flight = InFlight::new(&self.state);
+
+        if self.state.0.finished.load(Ordering::SeqCst) {
+            return Err(Error::SessionFinished);
+        }
+
+        self.state.0.handled.fetch_add(1, Ordering::SeqCst);
+        Ok(in_flight)
+    }
+
+    // Count a request to look up in the cassette, failing once there are
+    // more than the limit.
+    pub(crate) fn count_replay(&self) -> Result<(), Error> {
+        let count = self.state.0.replay_requests.fetch_add(1, Ordering::SeqCst)
+            + 1;
+
+        match self.max_requests {
+            Some(max) if count > max =>
+                Err(Error::RequestLimitExceeded(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // Anonymize, redact, and modify a request before it's recorded or
+    // matched.
+    pub(crate) fn prepare_request(&self, request: VcrRequest) -> VcrRequest {
+        let mut request = match &self.anonymize {
+            Some(config) => config.anonymize_request(&request),
+            None => request,
+        };
+
+        request.url = crate::rewrite::redact_query(
+            &request.url,
+            &self.redacted_query_params
+        );
+
+        for hook in self.request_hooks.iter() {
+            (hook.0)(&mut request);
+        }
+
+        request
+    }
+
+    // Prepare a response to the request for recording, failing if it must not
+    // be recorded.
+    pub(crate) fn prepare_response(
+        &self,
+        request: &VcrRequest,
+        response: VcrResponse,
+        tracing_id: Option<String>,
+        started_at: u64,
+    ) -> Result<VcrResponse, Error> {
+        let mut response = match &self.anonymize {
+            Some(config) => config.anonymize_response(&response),
+            None => response,
+        };
+
+        if let Some(headers) = encoding::consistent_encoding(
+            &response.headers,
+            &response.body
+        ) {
+            log::debug!(
+                "Response from {} was decoded by the client; not \
+                recording its Content-Encoding",
+                request.url
+            );
+            response.headers = headers;
+        }
+
+        let framing = consistent_framing(
+            &response.headers,
+            response.body.known_len()
+        );
+
+        if let Some(headers) = framing {
+            log::warn!(
+                "Response from {} has both Content-Length and chunked \
+                Transfer-Encoding headers; recording only one",
+                request.url
+            );
+            response.headers = headers;
+        }
+
+        for hook in self.response_hooks.iter() {
+            (hook.0)(&mut response);
+        }
+
+        if self.fail_on_secrets {
+            let secret = secrets::scan_response(
+                &response,
+                &self.secret_allowlist
+            );
+
+            if let Some(secret) = secret {
+                return Err(
+                    Error::SuspectedSecret(request.url.clone(), secret)
+                );
+            }
+        }
+
+        if let Some(inconsistency) = self.check_consistency(request, &response)
+        {
+            if self.fail_on_inconsistent {
+                return Err(Error::InconsistentRecording(inconsistency));
+            }
+        }
+
+        response.title = self.state.0.next_title.lock().unwrap().take();
+        response.tracing_id = tracing_id;
+        // The sequence number is assigned when the interaction is
+        // written, and the end of a streamed response when its body
+        // has been read.
+        response.timing = Some(InteractionTiming {
+            sequence: 0,
+            started_at,
+            finished_at: self.state.0.clock.elapsed_millis(),
+        });
+
+        Ok(response)
+    }
+
+    // The request whose recording a new recording of the request replaces,
+    // according to the retry policy.
+    pub(crate) fn retried(&self, request: &VcrRequest) -> Option<VcrRequest> {
+        match self.retry_policy {
+            RetryPolicy::KeepLast => Some(request.clone()),
+            RetryPolicy::KeepAll => None,
+        }
+    }
+
+    // Find the interaction to replay for the request, checking its contracts
+    // and order constraints.
+    pub(crate) fn select_interaction(
+        &self,
+        cassette: &IndexedCassette,
+        request: &VcrRequest
+    ) -> Result<Option<usize>, Error> {
+        let pos = match (self.replay_strategy, &self.correlation_header) {
+            (ReplayStrategy::Sequential, _) =>
+                self.next_in_sequence(cassette, request),
+            (_, Some(name)) => self.find_correlated(cassette, request, name),
+            (_, None) => self.find_unplayed(cassette, request),
+        };
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+
+        #[cfg(feature = "contracts")]
+        for contract in self.contracts.iter() {
+            contract.check(
+                &cassette.requests[pos].url,
+                &cassette.responses[pos].body
+            )?;
+        }
+
+        let unmet = cassette.unmet_dependency(
+            pos,
+            &self.state.0.played.lock().unwrap()
+        );
+
+        match unmet {
+            Some(before) => Err(Error::OrderViolation(pos, before)),
+            None => Ok(Some(pos)),
+        }
+    }
+
+    // Mark the interaction as replayed and return its response, as modified
+    // by the replay interceptor.
+    pub(crate) fn take_response<'a>(
+        &self,
+        cassette: &'a IndexedCassette,
+        request: &VcrRequest,
+        pos: usize
+    ) -> Cow<'a, VcrResponse> {
+        self.state.0.played.lock().unwrap().insert(pos);
+
+        match &self.replay_interceptor {
+            Some(intercept) => {
+                let mut state = self.state.0.intercept_state.lock().unwrap();
+
+                Cow::Owned((intercept.0)(
+                    request,
+                    cassette.responses[pos].clone(),
+                    &mut state
+                ))
+            },
+            None => Cow::Borrowed(&cassette.responses[pos]),
+        }
+    }
+
+    // Whether a recorded request matches the request being replayed. The
+    // correlation header is not compared.
+    pub(crate) fn request_matches(
+        &self,
+        recorded: &VcrRequest,
+        request: &VcrRequest
+    ) -> bool {
+        if let Some(matcher) = &self.request_matcher {
+            return (matcher.0)(recorded, request);
+        }
+
+        let opts = &self.match_options;
+
+        let mut ignored_headers = self.correlation_header.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+
+        let conditional_match = match self.conditional_headers {
+            Some(true) => CONDITIONAL_HEADERS.iter()
+                .all(|h| recorded.header_value(h) == request.header_value(h)),
+            Some(false) => {
+                ignored_headers.extend_from_slice(CONDITIONAL_HEADERS);
+                true
+            },
+            None => true,
+        };
+
+        (! opts.match_method || recorded.method == request.method)
+            && opts.urls_match(
+                &self.match_url(&recorded.url),
+                &self.match_url(&request.url)
+            )
+            && conditional_match
+            && opts.headers_match(recorded, request, &ignored_headers)
+            && (! opts.match_body || self.bodies_match(recorded, request))
+    }
+
+    // The form of a URL that is compared when matching; older cassettes may
+    // have recorded fragments or query values that are now redacted.
+    pub(crate) fn match_url(&self, url: &Url) -> Url {
+        rewrite::redact_query(
+            &without_fragment(url),
+            &self.redacted_query_params
+        )
+    }
+
+    fn bodies_match(&self, recorded: &VcrRequest, request: &VcrRequest)
+    -> bool {
+        let matcher = request.content_type().and_then(|content_type| {
+            self.body_matchers.iter().find(|(t, _)| t == &content_type)
+        });
+
+        match (matcher, recorded.body.bytes(), request.body.bytes()) {
+            (Some((_, matcher)), Some(rec), Some(req)) =>
+                matcher.0.matches(rec, req),
+            _ => recorded.body == request.body,
+        }
+    }
+
+    // Whether the interaction at `pos` can be replayed for the request: the
+    // requests match, including any headers the response varies on.
+    fn interaction_matches(
+        &self,
+        cassette: &VcrCassette,
+        pos: usize,
+        request: &VcrRequest
+    ) -> bool {
+        let recorded = &cassette.requests[pos];
+
+        self.request_matches(recorded, request)
+            && vary_matches(recorded, &cassette.responses[pos], request)
+    }
+
+    // Take the next interaction in the cassette, if it matches the request.
+    fn next_in_sequence(&self, cassette: &VcrCassette, request: &VcrRequest)
+    -> Option<usize> {
+        let pos = self.state.0.cursor.fetch_add(1, Ordering::SeqCst);
+
+        Some(pos).filter(|pos| {
+            *pos < cassette.len()
+                && self.interaction_matches(cassette, *pos, request)
+        })
+    }
+
+    // The positions of the recorded requests that could match the request, in
+    // recorded order. When the method and URL are compared, only the requests
+    // with the same method and URL are searched.
+    fn candidates<'a>(
+        &self,
+        cassette: &'a IndexedCassette,
+        request: &VcrRequest
+    ) -> Cow<'a, [usize]> {
+        let opts = &self.match_options;
+
+        if self.request_matcher.is_none() && opts.match_method && opts.match_url
+        {
+            Cow::Borrowed(cassette.candidates(request))
+        } else {
+            Cow::Owned((0..cassette.len()).collect())
+        }
+    }
+
+    // Search for the first request that hasn't been replayed, falling back to
+    // the last match according to the repeat policy.
+    fn find_unplayed(&self, cassette: &IndexedCassette, request: &VcrRequest)
+    -> Option<usize> {
+        let played = self.state.0.played.lock().unwrap();
+        let mut last = None;
+
+        for &pos in self.candidates(cassette, request).iter() {
+            if self.interaction_matches(cassette, pos, request) {
+                if ! played.contains(&pos) { return Some(pos); }
+                last = Some(pos);
+            }
+        }
+
+        match self.repeat_policy {
+            RepeatPolicy::RepeatLast => last,
+            RepeatPolicy::Unmatched => None,
+        }
+    }
+
+    // Search for a request, linking the client's correlation ID to a recorded
+    // ID.
+    fn find_correlated(
+        &self,
+        cassette: &IndexedCassette,
+        request: &VcrRequest,
+        header: &str
+    ) -> Option<usize> {
+        let recorded = &cassette.requests;
+        let mut correlations = self.state.0.correlations.lock().unwrap();
+
+        let live_id = request.header_value(header);
+        let linked_id = live_id.as_ref()
+            .and_then(|id| correlations.get(id))
+            .cloned();
+
+        let used_ids = correlations.values().collect::<HashSet<_>>();
+
+        let candidates = self.candidates(cassette, request);
+
+        let pos = candidates.iter().copied().find(|&pos| {
+            if ! self.interaction_matches(cassette, pos, request) {
+                return false;
+            }
+
+            match (&live_id, recorded[pos].header_value(header)) {
+                (None, None) => true,
+                (Some(_), Some(rec_id)) => match &linked_id {
+                    Some(linked) => linked == &rec_id,
+                    None => ! used_ids.contains(&rec_id),
+                },
+                _ => false,
+            }
+        });
+
+        if let (Some(pos), Some(live_id), None) = (pos, live_id, linked_id) {
+            if let Some(rec_id) = recorded[pos].header_value(header) {
+                correlations.insert(live_id, rec_id);
+            }
+        }
+
+        pos
+    }
+
+    // Describe the interactions in the cassette that the session has not
+    // replayed.
+    pub(crate) async fn unplayed(&self) -> Vec<String> {
+        if ! matches!(self.mode, VcrMode::Replay | VcrMode::NewEpisodes) {
+            return vec![];
+        }
+
+        let played = self.state.0.played.lock().unwrap().clone();
+        let describe = |cassette: &VcrCassette| {
+            cassette.requests.iter()
+                .enumerate()
+                .filter(|(i, _)| ! played.contains(i))
+                .map(|(i, req)| format!("  #{} {} {}", i, req.method, req.url))
+                .collect()
+        };
+
+        if let Some(memory) = &self.memory {
+            return describe(&memory.0);
+        }
+
+        let cassettes = match CASSETTES.get() {
+            Some(cassettes) => cassettes.read().await,
+            None => return vec![],
+        };
+
+        match cassettes.get(&self.file) {
+            Some(session) =>
+                session.read().await.as_ref()
+                    .map_or(vec![], |cassette| describe(cassette)),
+            None => vec![],
+        }
+    }
+
+    // Generate a unique body file path, relative to the cassette's directory.
+    pub(crate) fn new_sidecar_file(&self) -> PathBuf {
+        let mut dir = self.file.file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_default();
+        dir.push(".bodies");
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let count = SIDECAR_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        PathBuf::from(dir).join(format!("{}-{}.body", time, count))
+    }
+
+    // Resolve a body file path relative to the cassette's directory.
+    pub(crate) fn sidecar_path(&self, file: &Path) -> PathBuf {
+        match self.file.parent() {
+            Some(dir) => dir.join(file),
+            None => file.to_owned(),
+        }
+    }
+
+    // Append a recorded interaction to the cassette or the record sink. If
+    // `retried` is the last request recorded, its interaction is replaced.
+    // The sequence number is assigned while the cassette is locked, so the
+    // numbers follow the order of the interactions in the cassette.
+    pub(crate) async fn write_interaction(
+        &self,
+        request: VcrRequest,
+        response: VcrResponse,
+        retried: Option<VcrRequest>
+    ) -> io::Result<()> {
+        // Writers are always given YAML; JSON cassettes don't share bodies.
+        let dedup_bodies = self.dedup_bodies
+            && (self.sink.is_some() || self.format == VcrFormat::Yaml);
+
+        // The interaction's document, preceded by a document sharing its body
+        // if the session hasn't written the body yet, and the hash of the
+        // newly shared body.
+        let document = |
+            request: VcrRequest,
+            mut response: VcrResponse,
+            shared: &HashSet<String>
+        | {
+            let shared_body = match dedup_bodies {
+                true => cassette::reference_body(&mut response, shared)
+                    .map_err(io::Error::other)?,
+                false => None,
+            };
+
+            let doc = match self.format {
+                VcrFormat::Json if self.sink.is_none() =>
+                    cassette::to_json_interaction(&request, &response)
+                        .map_err(io::Error::other),
+                _ => cassette::to_document(&(
+                    SerdeWrapper::Request(request),
+                    SerdeWrapper::Response(response)
+                )).map_err(io::Error::other),
+            }?;
+
+            Ok::<_, io::Error>(match shared_body {
+                Some((hash, body_doc)) => (body_doc + &doc, Some(hash)),
+                None => (doc, None),
+            })
+        };
+
+        let mut response = response;
+
+        if let Some(sink) = &self.sink {
+            let mut sink = sink.0.lock().await;
+            self.set_sequence(&mut response);
+
+            let (doc, shared_body) = document(
+                request,
+                response,
+                &self.state.0.shared_bodies.lock().unwrap()
+            )?;
+            self.state.0.shared_bodies.lock().unwrap().extend(shared_body);
+
+            sink.write_all(doc.as_bytes()).await?;
+            return sink.flush().await;
+        }
+
+        let recorders = CASSETTES.get().unwrap().read().await;
+        let mut lock = recorders[&self.file].write().await;
+        self.set_sequence(&mut response);
+
+        // A cassette loaded to replay it is kept in step with its file.
+        let loaded = lock.as_ref()
+            .map(|_| (request.clone(), response.clone()));
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file).await?;
+
+        let end = file.metadata().await?.len();
+
+        // Overwrite the previous attempt of a retried request, unless another
+        // session has written to the file since.
+        let replaced = retried.as_ref().and_then(|request| {
+            self.state.0.last_recorded.lock().unwrap().as_ref()
+                .filter(|last| &last.request == request && last.end == end)
+                .map(|last| (last.start, last.shared_body.clone()))
+        });
+
+        let start = match &replaced {
+            Some((start, shared_body)) => {
+                // A body first shared by the replaced interaction is removed
+                // with it.
+                if let Some(hash) = shared_body {
+                    self.state.0.shared_bodies.lock().unwrap().remove(hash);
+                }
+
+                *start
+            },
+            None => end,
+        };
+
+        let (doc, shared_body) = document(
+            request,
+            response,
+            &self.state.0.shared_bodies.lock().unwrap()
+        )?;
+
+        let (written_at, written_end) = match self.format {
+            VcrFormat::Yaml => {
+                if start < end {
+                    file.set_len(start).await?;
+                }
+
+                // Each record is a new YAML document.
+                file.write_all(doc.as_bytes()).await?;
+                file.flush().await?;
+                (start, start + doc.len() as u64)
+            },
+            VcrFormat::Json => {
+                drop(file);
+                let written_at = cassette::append_json(&self.file, start, &doc)
+                    .await?;
+                (written_at, fs::metadata(&self.file).await?.len())
+            },
+        };
+
+        self.state.0.shared_bodies.lock().unwrap()
+            .extend(shared_body.iter().cloned());
+
+        if let Some(request) = retried {
+            *self.state.0.last_recorded.lock().unwrap() = Some(LastRecorded {
+                request,
+                start: written_at,
+                end: written_end,
+                shared_body,
+            });
+        }
+
+        if let (Some((request, response)), Some(cassette))
+            = (loaded, lock.as_mut())
+        {
+            if replaced.is_some() {
+                cassette.replace_last(response);
+            } else {
+                cassette.push(request, response);
+            }
+
+            // New episodes are replayed for the rest of the session.
+            if self.mode == VcrMode::NewEpisodes {
+                self.state.0.played.lock().unwrap().insert(cassette.len() - 1);
+            }
+        }
+        drop(lock);
+
+        Ok(())
+    }
+
+    // Assign the response the next sequence number.
+    fn set_sequence(&self, response: &mut VcrResponse) {
+        let sequence = self.state.0.next_sequence.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(timing) = &mut response.timing {
+            timing.sequence = sequence;
+        }
+    }
+}
+
+// Remove a cassette file to record it again, holding the cassettes' lock so
+// no interactions are written to it meanwhile.
+pub(crate) async fn remove_cassette(path: &Path) -> io::Result<()> {
+    // Ignore error; we only initialize once.
+    let _ = CASSETTES.set(RwLock::new(HashMap::new()));
+
+    let _cassettes = CASSETTES.get().unwrap().write().await;
+    remove_recording(path).await
+}
+
+async fn remove_recording(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Scratch;
+
+    fn get(path: &str) -> VcrRequest {
+        VcrRequest::new(
+            Method::Get,
+            Url::parse("https://example.com").unwrap().join(path).unwrap()
+        )
+    }
+
+    #[async_std::test]
+    async fn record_and_replay_without_surf() -> Result<(), Error> {
+        let path = "test-sessions/scratch/core-test.yml";
+        let _path = Scratch::new(path);
+
+        let session = Session::new(VcrMode::Once, path).await?
+            .with_modify_request(|req| { req.headers.remove("x-token"); });
+        assert_eq!(session.mode(), VcrMode::Record);
+
+        // The adapter sends requests that aren't replayed to the server.
+        let request = get("/items").with_header("X-Token", "s3cr3t");
+        assert_eq!(session.replay(request.clone()).await?, None);
+
+        let response = VcrResponse::new(StatusCode::Ok)
+            .with_header("content-type", "application/json")
+            .with_body(Body::from(r#"["widget"]"#.as_bytes()));
+        session.record(request, response).await?;
+
+        session.record(
+            get("/items/1"),
+            VcrResponse::new(StatusCode::NotFound)
+        ).await?;
+
+        let report = session.finish().await;
+        assert_eq!(report.requests, 3);
+
+        let session = Session::new(VcrMode::Once, path).await?;
+        assert_eq!(session.mode(), VcrMode::Replay);
+
+        let interactions = session.interactions().await;
+        assert_eq!(interactions.len(), 2);
+        assert_eq!(interactions[0].request.headers().count(), 0);
+
+        let response = session.replay(get("/items")).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.body_str(), Some(r#"["widget"]"#));
+
+        let response = session.replay(get("/items/1")).await?.unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+
+        assert!(matches!(
+            session.replay(get("/other")).await,
+            Err(Error::NoMatchingRequest { recorded: 2, .. })
+        ));
+
+        // New episodes are sent to the server instead.
+        let episodes = Session::new(VcrMode::NewEpisodes, path).await?;
+        assert_eq!(episodes.replay(get("/other")).await?, None);
+
+        session.finish().await;
+        assert!(matches!(
+            session.replay(get("/items")).await,
+            Err(Error::SessionFinished)
+        ));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn collapse_retries_only_at_end_of_file() -> Result<(), Error> {
+        for path in [
+            "test-sessions/scratch/core-retry-test.yml",
+            "test-sessions/scratch/core-retry-test.json",
+        ] {
+            let _path = Scratch::new(path);
+
+            let first = Session {
+                retry_policy: RetryPolicy::KeepLast,
+                ..Session::new(VcrMode::Record, path).await?
+            };
+            let second = Session::new(VcrMode::Record, path).await?;
+
+            let unavailable = VcrResponse::new(StatusCode::ServiceUnavailable);
+            first.record(get("/a"), unavailable.clone()).await?;
+            first.record(get("/a"), unavailable).await?;
+
+            // The retry can't replace the first attempt without removing the
+            // other session's interaction.
+            second.record(get("/b"), VcrResponse::new(StatusCode::Ok)).await?;
+            first.record(get("/a"), VcrResponse::new(StatusCode::Ok)).await?;
+
+            let cassette = VcrCassette::load(path).await?;
+            let recorded = cassette.requests.iter()
+                .zip(cassette.responses.iter())
+                .map(|(req, res)| (req.url.path(), res.status))
+                .collect::<Vec<_>>();
+
+            assert_eq!(recorded, vec![
+                ("/a", StatusCode::ServiceUnavailable),
+                ("/b", StatusCode::Ok),
+                ("/a", StatusCode::Ok),
+            ]);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn replay_after_recording_to_loaded_cassette() -> Result<(), Error> {
+        let path = "test-sessions/scratch/core-reload-test.yml";
+        let _path = Scratch::new(path);
+
+        let session = Session::new(VcrMode::Record, path).await?;
+        session.record(get("/a"), VcrResponse::new(StatusCode::Ok)).await?;
+        session.finish().await;
+
+        let session = Session::new(VcrMode::Replay, path).await?;
+        assert!(session.replay(get("/a")).await?.is_some());
+        session.finish().await;
+
+        let session = Session::new(VcrMode::Record, path).await?;
+        session.record(get("/b"), VcrResponse::new(StatusCode::Ok)).await?;
+        session.finish().await;
+
+        // The loaded cassette has the interaction recorded since.
+        let session = Session::new(VcrMode::Replay, path).await?;
+        assert_eq!(session.interactions().await.len(), 2);
+        assert!(session.replay(get("/b")).await?.is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use crate::Body;
+
+
+// HTTP clients usually decode compressed bodies but leave the
+// `Content-Encoding` header in place, so a recorded response may claim an
+// encoding its body no longer has. We record either the raw body with its
+// header, or the decoded body without it.
+//
+// Returns the corrected headers if the body has been decoded; the
+// `Content-Length`, which was the length of the encoded body, is removed too.
+pub(crate) fn consistent_encoding(
+    headers: &HashMap<String, Vec<String>>,
+    body: &Body
+) -> Option<HashMap<String, Vec<String>>> {
+    let bytes = body.bytes()?;
+
+    let encodings = headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+        .flat_map(|(_, values)| values.iter())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| ! v.is_empty() && v != "identity")
+        .collect::<Vec<_>>();
+
+    // With several encodings, the last was applied last.
+    let outer = encodings.last()?;
+
+    if ! is_decoded(outer, bytes) { return None; }
+
+    let mut headers = headers.clone();
+    headers.retain(|name, _| {
+        ! name.eq_ignore_ascii_case("content-encoding")
+            && ! name.eq_ignore_ascii_case("content-length")
+    });
+
+    Some(headers)
+}
+
+// Whether a body declared with the given encoding has been decoded. Formats
+// with a magic number are checked for it; Brotli has none, so a Brotli body
+// is considered decoded if it's text, which compressed data almost never is.
+fn is_decoded(encoding: &str, body: &[u8]) -> bool {
+    if body.is_empty() { return false; }
+
+    match encoding {
+        "gzip" | "x-gzip" => ! body.starts_with(&[0x1f, 0x8b]),
+        "deflate" => ! is_zlib_header(body),
+        "zstd" => ! body.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]),
+        "br" | "compress" | "x-compress" => std::str::from_utf8(body).is_ok(),
+        // We don't know what it should look like.
+        _ => false,
+    }
+}
+
+fn is_zlib_header(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8
+            && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(encoding: &str) -> HashMap<String, Vec<String>> {
+        [
+            ("Content-Encoding", encoding),
+            ("Content-Length", "12"),
+            ("Content-Type", "text/plain"),
+        ].iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn remove_encoding_of_decoded_bodies() {
+        let decoded = Body::Str("Hello, world".to_owned());
+
+        for encoding in &["br", "gzip", "deflate", "zstd", "gzip, br"] {
+            let fixed = consistent_encoding(&headers(encoding), &decoded)
+                .unwrap();
+
+            assert_eq!(fixed.len(), 1);
+            assert!(fixed.contains_key("Content-Type"));
+        }
+    }
+
+    #[test]
+    fn keep_encoding_of_raw_bodies() {
+        let gzip = Body::Bytes(vec![0x1f, 0x8b, 0x08, 0x00, 0xff]);
+        assert_eq!(consistent_encoding(&headers("gzip"), &gzip), None);
+
+        let deflate = Body::Bytes(vec![0x78, 0x9c, 0xcb, 0x48, 0xcd]);
+        assert_eq!(consistent_encoding(&headers("deflate"), &deflate), None);
+
+        let br = Body::Bytes(vec![0x1b, 0xff, 0xa0, 0x00, 0xc8, 0xc3]);
+        assert_eq!(consistent_encoding(&headers("br"), &br), None);
+
+        let text = Body::Str("Hello, world".to_owned());
+        assert_eq!(consistent_encoding(&headers("identity"), &text), None);
+        assert_eq!(consistent_encoding(&headers("unknown"), &text), None);
+    }
+}
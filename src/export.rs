@@ -0,0 +1,556 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    env,
+    io,
+    path::PathBuf,
+};
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{Body, VcrCassette, VcrRequest};
+
+
+/// The response headers included in OpenAPI examples (see
+/// [VcrCassette::to_openapi_examples]).
+pub const OPENAPI_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-language",
+    "content-type",
+    "etag",
+    "link",
+    "location",
+    "retry-after",
+];
+
+
+impl VcrCassette {
+    /// Generate a `curl` command to reproduce each recorded request.
+    ///
+    /// Text bodies are sent as they are with `--data-raw`. Binary request
+    /// bodies are written to files in the system's temporary directory, named
+    /// after a hash of their contents, and referenced via
+    /// `--data-binary @<file>`; bodies stored in separate files are referenced
+    /// by their path, which is relative to the cassette's directory. A binary
+    /// body whose file cannot be written is piped to `curl` with `printf`.
+    ///
+    /// ```
+    /// # async fn export() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+    ///
+    /// for cmd in cassette.to_curl_commands() {
+    ///     println!("{}", cmd);
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn to_curl_commands(&self) -> Vec<String> {
+        self.requests.iter()
+            .map(|req| curl_command(self, req))
+            .collect()
+    }
+
+    /// Generate an [HTTPie](https://httpie.io) command to reproduce each
+    /// recorded request.
+    ///
+    /// JSON object bodies are converted to request items (`key=value` for
+    /// strings and `key:=value` for other values); other text bodies are sent
+    /// with `--raw`. Binary bodies are handled as in [to_curl_commands], and
+    /// referenced with an `@<file>` item.
+    ///
+    /// [to_curl_commands]: VcrCassette::to_curl_commands
+    pub fn to_httpie_commands(&self) -> Vec<String> {
+        self.requests.iter()
+            .map(|req| httpie_command(self, req))
+            .collect()
+    }
+
+    /// Generate OpenAPI examples of each recorded interaction, grouped by the
+    /// path template in `spec_paths` that its URL matches (such as
+    /// `/users/{id}`) and its method.
+    ///
+    /// The result is a JSON object in the shape of an OpenAPI `paths` object,
+    /// containing only the `requestBody` and `responses` of each operation.
+    /// Each example is named after the interaction's position in the
+    /// cassette, as `interaction_N`.
+    ///
+    /// Bodies are included as JSON if they parse as JSON, and as base64
+    /// strings otherwise; bodies stored in separate files are referenced by
+    /// `externalValue`. Only the response headers in [OPENAPI_HEADERS] are
+    /// included. Interactions whose paths match no template are skipped; the
+    /// first template that matches is used, so list specific paths (like
+    /// `/users/me`) before templated ones.
+    pub fn to_openapi_examples(&self, spec_paths: &[&str]) -> Value {
+        let mut paths = Value::Object(Map::new());
+
+        for (i, (req, res)) in
+            self.requests.iter().zip(self.responses.iter()).enumerate()
+        {
+            let template = match spec_paths.iter()
+                .find(|t| path_matches_template(req.url.path(), t))
+            {
+                Some(template) => *template,
+                None => continue,
+            };
+
+            let name = format!("interaction_{}", i);
+            let method = req.method.to_string().to_ascii_lowercase();
+
+            if let Some(example) = openapi_example(&req.body) {
+                let media_type = media_type(&req.headers);
+
+                object_at(&mut paths, &[
+                    template, &method, "requestBody", "content", &media_type,
+                    "examples",
+                ]).insert(name.clone(), example);
+            }
+
+            let status = u16::from(res.status).to_string();
+
+            if let Some(example) = openapi_example(&res.body) {
+                let media_type = media_type(&res.headers);
+
+                object_at(&mut paths, &[
+                    template, &method, "responses", &status, "content",
+                    &media_type, "examples",
+                ]).insert(name.clone(), example);
+            }
+
+            let mut names = res.headers.keys()
+                .filter(|n| {
+                    OPENAPI_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(n))
+                })
+                .collect::<Vec<_>>();
+            names.sort();
+
+            for header in names {
+                let example = serde_json::json!({
+                    "example": res.headers[header].join(", "),
+                });
+
+                object_at(&mut paths, &[
+                    template, &method, "responses", &status, "headers",
+                ]).insert(header.to_ascii_lowercase(), example);
+            }
+
+            // Responses without a body or headers are still documented.
+            object_at(&mut paths, &[template, &method, "responses", &status]);
+        }
+
+        paths
+    }
+}
+
+// Whether a URL path matches an OpenAPI path template, in which each
+// `{parameter}` segment matches any non-empty segment.
+fn path_matches_template(path: &str, template: &str) -> bool {
+    let path = path.trim_end_matches('/').split('/').collect::<Vec<_>>();
+    let template = template.trim_end_matches('/').split('/')
+        .collect::<Vec<_>>();
+
+    path.len() == template.len()
+        && path.iter().zip(template.iter()).all(|(p, t)| {
+            if t.starts_with('{') && t.ends_with('}') {
+                ! p.is_empty()
+            } else {
+                p == t
+            }
+        })
+}
+
+// An OpenAPI Example object for a body, or `None` if the body is empty.
+fn openapi_example(body: &Body) -> Option<Value> {
+    let value = match body {
+        Body::Str(s) if s.is_empty() => return None,
+        Body::Str(s) => serde_json::from_str(s)
+            .unwrap_or_else(|_| Value::String(base64::encode(s))),
+        Body::Bytes(b) if b.is_empty() => return None,
+        Body::Bytes(b) => Value::String(base64::encode(b)),
+        Body::File { file, .. } => return Some(serde_json::json!({
+            "externalValue": file.display().to_string(),
+        })),
+        Body::Ref { .. } => return None,
+    };
+
+    Some(serde_json::json!({ "value": value }))
+}
+
+fn media_type(headers: &HashMap<String, Vec<String>>) -> String {
+    headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, values)| values.first())
+        .map(|v| v.split(';').next().unwrap_or_default().trim().to_owned())
+        .unwrap_or_else(|| "application/octet-stream".to_owned())
+}
+
+// The object at the path of keys in `value`, creating any missing objects.
+fn object_at<'a>(value: &'a mut Value, keys: &[&str])
+-> &'a mut Map<String, Value> {
+    let mut value = value;
+
+    for key in keys {
+        value = value.as_object_mut()
+            .expect("OpenAPI examples are nested objects")
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    value.as_object_mut().expect("OpenAPI examples are nested objects")
+}
+
+fn curl_command(cassette: &VcrCassette, req: &VcrRequest) -> String {
+    let mut cmd = format!("curl -X {}", req.method);
+
+    for (name, value) in sorted_headers(req) {
+        let header = format!("{}: {}", name, value);
+        cmd.push_str(&format!(" -H {}", shell_quote(&header)));
+    }
+
+    match &req.body {
+        Body::Str(s) if s.is_empty() => {},
+        Body::Str(s) => {
+            cmd.push_str(&format!(" --data-raw {}", shell_quote(s)));
+        },
+        Body::Bytes(b) => match write_binary_body(b) {
+            Ok(path) => cmd.push_str(&format!(
+                " --data-binary {}",
+                shell_quote(&format!("@{}", path.display()))
+            )),
+            Err(_) => {
+                let printf = printf_command(b);
+                cmd = format!("{} | {} --data-binary @-", printf, cmd);
+            },
+        },
+        Body::File { file, .. } => cmd.push_str(&format!(
+            " --data-binary {}",
+            shell_quote(&format!("@{}", cassette.body_path(file).display()))
+        )),
+        Body::Ref { .. } => {},
+    }
+
+    cmd.push_str(&format!(" {}", shell_quote(req.url.as_str())));
+    cmd
+}
+
+fn httpie_command(cassette: &VcrCassette, req: &VcrRequest) -> String {
+    let mut args = vec![];
+    let mut items = vec![];
+    let mut input = None;
+    let mut printf = None;
+
+    match &req.body {
+        Body::Str(s) if s.is_empty() => {},
+        Body::Str(s) => match serde_json::from_str::<Value>(s) {
+            Ok(Value::Object(obj)) => {
+                for (key, value) in obj.iter() {
+                    items.push(match value {
+                        Value::String(v) => format!("{}={}", key, v),
+                        v => format!("{}:={}", key, v),
+                    });
+                }
+            },
+            _ => {
+                args.push("--raw".to_owned());
+                args.push(shell_quote(s));
+            },
+        },
+        Body::Bytes(b) => match write_binary_body(b) {
+            Ok(path) => input = Some(path),
+            Err(_) => printf = Some(printf_command(b)),
+        },
+        Body::File { file, .. } => input = Some(cassette.body_path(file)),
+        Body::Ref { .. } => {},
+    }
+
+    let headers = sorted_headers(req).into_iter()
+        .map(|(name, value)| {
+            // An empty value (`Name:`) would tell HTTPie to remove the header.
+            if value.is_empty() {
+                format!("{};", name)
+            } else {
+                format!("{}:{}", name, value)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(path) = input {
+        items.push(format!("@{}", path.display()));
+    }
+
+    // A body that couldn't be saved is piped to the command's standard input.
+    let mut cmd = match printf {
+        Some(printf) => format!("{} | http", printf),
+        None => String::from("http"),
+    };
+
+    for arg in args.iter()
+        .cloned()
+        .chain(vec![req.method.to_string(), shell_word(req.url.as_str())])
+        .chain(headers.iter().chain(items.iter()).map(|i| shell_word(i)))
+    {
+        cmd.push(' ');
+        cmd.push_str(&arg);
+    }
+
+    cmd
+}
+
+// Write a binary body to a temporary file for use in a generated command.
+// The file is named after the body's hash, so bodies exported from different
+// cassettes or at the same time don't overwrite each other.
+fn write_binary_body(body: &[u8]) -> io::Result<PathBuf> {
+    let hash = format!("{:x}", Sha256::digest(body));
+    let path = env::temp_dir().join(format!("surf-vcr-body-{}", &hash[..16]));
+
+    std::fs::write(&path, body)?;
+    Ok(path)
+}
+
+// A `printf` command writing a binary body to its standard output, for when
+// the body can't be written to a file.
+fn printf_command(body: &[u8]) -> String {
+    let format = body.iter()
+        .map(|b| match *b {
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("\\{:03o}", b),
+        })
+        .collect::<String>();
+
+    format!("printf '{}'", format)
+}
+
+// Headers sorted by name, with each value of a multi-valued header separate.
+fn sorted_headers(req: &VcrRequest) -> Vec<(&str, &str)> {
+    let mut names = req.headers.keys().collect::<Vec<_>>();
+    names.sort();
+
+    names.into_iter()
+        .flat_map(|name| {
+            req.headers[name].iter().map(move |v| (name.as_str(), v.as_str()))
+        })
+        .collect()
+}
+
+// Quote a string for a POSIX shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+// Quote a string for a POSIX shell only if necessary.
+fn shell_word(s: &str) -> String {
+    let safe = ! s.is_empty() && s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+
+    if safe { s.to_owned() } else { shell_quote(s) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::{http::Method, Url};
+
+    #[async_std::test]
+    async fn export_curl_commands() -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        assert_eq!(cassette.to_curl_commands(), vec![
+            "curl -X GET -H 'X-some-header: hello' --data-raw 'My Request' \
+                'https://example.com/'",
+            "curl -X GET -H 'x-some-header: another hello' \
+                'https://example.com/'",
+            "curl -X GET -H 'content-type: application/octet-stream' \
+                -H 'x-some-header: another hello' 'https://example.com/'",
+        ]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn curl_commands_quote_and_save_binary_bodies()
+    -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let mut headers = HashMap::new();
+        headers.insert(
+            "content-type".to_owned(),
+            vec!["application/json".to_owned()]
+        );
+
+        let json = VcrRequest {
+            method: Method::Post,
+            url: Url::parse("https://api.example.com/endpoint").unwrap(),
+            headers,
+            body: Body::Str(r#"{"key":"it's"}"#.to_owned()),
+        };
+
+        assert_eq!(
+            curl_command(&cassette, &json),
+            r#"curl -X POST -H 'content-type: application/json' "#.to_owned()
+                + r#"--data-raw '{"key":"it'\''s"}' "#
+                + "'https://api.example.com/endpoint'"
+        );
+
+        // Text bodies are sent as they are, even if curl would read them as
+        // a file name.
+        let text = VcrRequest {
+            body: Body::Str("@name\nsecond line".to_owned()),
+            ..json.clone()
+        };
+
+        assert!(curl_command(&cassette, &text)
+            .contains("--data-raw '@name\nsecond line'"));
+
+        let binary = VcrRequest {
+            method: Method::Put,
+            url: Url::parse("https://api.example.com/blob").unwrap(),
+            headers: HashMap::new(),
+            body: Body::Bytes(vec![0xff, 0x00, 0xfe]),
+        };
+
+        let path = write_binary_body(&[0xff, 0x00, 0xfe])?;
+        assert_ne!(path, write_binary_body(&[0xff, 0x00])?);
+
+        assert_eq!(
+            curl_command(&cassette, &binary),
+            format!(
+                "curl -X PUT --data-binary '@{}' \
+                    'https://api.example.com/blob'",
+                path.display()
+            )
+        );
+        assert_eq!(std::fs::read(path)?, vec![0xff, 0x00, 0xfe]);
+
+        // Body files are relative to the cassette.
+        let file = VcrRequest {
+            body: Body::File {
+                file: PathBuf::from("bodies/blob.bin"),
+                len: None,
+                sha256: None,
+            },
+            ..binary
+        };
+
+        assert_eq!(
+            curl_command(&cassette, &file),
+            "curl -X PUT --data-binary '@test-sessions/bodies/blob.bin' \
+                'https://api.example.com/blob'"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_binary_bodies_with_printf() {
+        assert_eq!(
+            printf_command(&[b'a', 0x00, b'%', b'\\', b'\'', 0xff]),
+            r"printf 'a\000\045\134\047\377'"
+        );
+    }
+
+    #[async_std::test]
+    async fn export_httpie_commands() -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        assert_eq!(cassette.to_httpie_commands(), vec![
+            "http --raw 'My Request' GET https://example.com/ \
+                X-some-header:hello",
+            "http GET https://example.com/ 'x-some-header:another hello'",
+            "http GET https://example.com/ \
+                content-type:application/octet-stream \
+                'x-some-header:another hello'",
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn httpie_commands_convert_json_bodies() {
+        let mut headers = HashMap::new();
+        headers.insert(
+            "Content-Type".to_owned(),
+            vec!["application/json".to_owned()]
+        );
+
+        let req = VcrRequest {
+            method: Method::Post,
+            url: Url::parse("https://api.example.com/endpoint").unwrap(),
+            headers,
+            body: Body::Str(r#"{"key":"val","count":3}"#.to_owned()),
+        };
+
+        assert_eq!(
+            httpie_command(&VcrCassette::default(), &req),
+            "http POST https://api.example.com/endpoint \
+                Content-Type:application/json count:=3 key=val"
+        );
+    }
+
+    #[async_std::test]
+    async fn httpie_commands_reference_body_files()
+    -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        let file = VcrRequest {
+            method: Method::Put,
+            url: Url::parse("https://api.example.com/blob").unwrap(),
+            headers: HashMap::new(),
+            body: Body::File {
+                file: PathBuf::from("bodies/blob.bin"),
+                len: None,
+                sha256: None,
+            },
+        };
+
+        assert_eq!(
+            httpie_command(&cassette, &file),
+            "http PUT https://api.example.com/blob \
+                @test-sessions/bodies/blob.bin"
+        );
+
+        let binary = VcrRequest {
+            body: Body::Bytes(vec![0xff, 0x00, 0xfe]),
+            ..file
+        };
+
+        let path = write_binary_body(&[0xff, 0x00, 0xfe])?;
+
+        assert_eq!(
+            httpie_command(&cassette, &binary),
+            format!(
+                "http PUT https://api.example.com/blob @{}",
+                path.display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn match_path_templates() {
+        assert!(path_matches_template("/users/123", "/users/{id}"));
+        assert!(path_matches_template("/users/", "/users"));
+        assert!(! path_matches_template("/users", "/users/{id}"));
+        assert!(! path_matches_template("/users/123/posts", "/users/{id}"));
+        assert!(! path_matches_template("/teams/123", "/users/{id}"));
+    }
+
+    #[async_std::test]
+    async fn export_openapi_examples() -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/openapi.yml").await?;
+        let golden = std::fs::read_to_string(
+            "test-sessions/openapi-examples.json"
+        )?;
+
+        assert_eq!(
+            cassette.to_openapi_examples(&["/users", "/users/{id}"]),
+            serde_json::from_str::<Value>(&golden).unwrap()
+        );
+
+        Ok(())
+    }
+}
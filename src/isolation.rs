@@ -0,0 +1,566 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError, Weak},
+};
+
+use async_std::{
+    future::poll_fn,
+    prelude::*,
+    sync::RwLock,
+    task::{Context, Poll},
+    fs,
+};
+use once_cell::sync::OnceCell;
+
+use crate::{
+    core::{self, Session},
+    has_recording,
+    SessionReport,
+    VcrCassette,
+    VcrError,
+    VcrFormat,
+    VcrMiddleware,
+    VcrMode,
+    CASSETTES,
+};
+
+// The scopes with recording middlewares, for flush_all_blocking.
+static RECORDING_SCOPES: OnceCell<Mutex<Vec<Weak<Mutex<ScopeState>>>>>
+    = OnceCell::new();
+
+/// Run `f` with a scope whose middlewares don't share state with any
+/// middleware created outside of it, then finish the scope.
+///
+/// See [IsolationScope] for the isolation it provides. To check the scope's
+/// session reports, call [IsolationScope::finish] within `f`. If `f` panics,
+/// the scope is finished, so its recordings are written, before the panic
+/// continues.
+///
+/// ```
+/// # async fn runtest() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::{isolated, VcrMode};
+///
+/// isolated(|ctx| async move {
+///     let vcr = ctx.middleware(VcrMode::Replay, "test-sessions/simple.yml")
+///         .await?;
+///     let client = surf::Client::new().with(vcr);
+///     // ...
+///     Ok::<_, surf_vcr::VcrError>(())
+/// }).await??;
+/// # Ok(()) }
+/// ```
+pub async fn isolated<F, Fut, T>(f: F) -> Result<T, VcrError>
+    where F: FnOnce(IsolationScope) -> Fut,
+          Fut: Future<Output = T>,
+{
+    let scope = IsolationScope::default();
+    let mut scoped = Box::pin(f(scope.clone()));
+
+    let output = poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| scoped.as_mut().poll(cx)))
+        {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }).await;
+
+    let finished = scope.finish().await;
+
+    match output {
+        Ok(output) => finished.map(|_| output),
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+/// A scope for middlewares that must not be affected by middlewares created
+/// elsewhere in the test binary, even those using the same cassette files;
+/// see [isolated].
+///
+/// - Replaying middlewares read their own copy of the cassette, and each has
+///   its own session state (such as linked correlation IDs and usage).
+/// - Recording middlewares record to a buffer; the scope's interactions are
+///   appended to the cassette together when the scope finishes, so they are
+///   not interleaved with other scopes' interactions.
+///
+/// In `All` mode, a cassette is replaced by the first middleware created for
+/// it in the scope; the scope's other middlewares add to it.
+///
+/// Clones of a scope refer to the same scope. If the last of them is dropped
+/// before the scope is finished, its recordings are written as by
+/// [flush_all_blocking].
+#[derive(Clone, Debug, Default)]
+pub struct IsolationScope(Arc<Mutex<ScopeState>>);
+
+#[derive(Debug, Default)]
+struct ScopeState {
+    middlewares: Vec<ScopedMiddleware>,
+    // The cassettes replaced in All mode.
+    replaced: HashSet<PathBuf>,
+}
+
+#[derive(Debug)]
+struct ScopedMiddleware {
+    vcr: VcrMiddleware,
+    // The interactions a recording middleware has recorded, and the cassette
+    // to write them to.
+    recording: Option<(PathBuf, ScopeBuffer)>,
+}
+
+impl IsolationScope {
+    /// Create a middleware in the scope.
+    pub async fn middleware<P>(&self, mode: VcrMode, cassette: P)
+    -> Result<VcrMiddleware, VcrError>
+        where P: Into<PathBuf>,
+    {
+        let path = cassette.into();
+
+        if mode == VcrMode::All {
+            let replace = self.0.lock().unwrap().replaced.insert(path.clone());
+
+            if replace {
+                core::remove_cassette(&path).await?;
+            }
+        }
+
+        let mode = mode.resolve(has_recording(&path));
+
+        let (vcr, recording) = match mode {
+            VcrMode::Replay => {
+                let cassette = VcrCassette::load(&path).await?;
+
+                let vcr = VcrMiddleware::with_session(Session {
+                    memory: Some(cassette.into_memory()),
+                    // Bodies in separate files are relative to the cassette.
+                    ..Session::with_options(mode, path)
+                });
+
+                (vcr, None)
+            },
+            VcrMode::Record => {
+                let buffer = ScopeBuffer::default();
+
+                let mut vcr = VcrMiddleware::record_to_writer(
+                    mode,
+                    Box::new(buffer.clone())
+                );
+                vcr.session.file = path.clone();
+
+                (vcr, Some((path, buffer)))
+            },
+            VcrMode::NewEpisodes => {
+                let cassette = match has_recording(&path) {
+                    true => VcrCassette::load(&path).await?,
+                    false => VcrCassette::default(),
+                };
+                let buffer = ScopeBuffer::default();
+
+                // Interactions recorded in the scope aren't replayed until
+                // the scope is finished.
+                let mut vcr = VcrMiddleware::record_to_writer(
+                    VcrMode::Record,
+                    Box::new(buffer.clone())
+                );
+                vcr.session.mode = VcrMode::NewEpisodes;
+                vcr.session.memory = Some(cassette.into_memory());
+                vcr.session.file = path.clone();
+
+                (vcr, Some((path, buffer)))
+            },
+            mode => (VcrMiddleware::with_options(mode, path), None),
+        };
+
+        let records = recording.is_some();
+
+        self.0.lock().unwrap().middlewares.push(ScopedMiddleware {
+            vcr: vcr.clone(),
+            recording,
+        });
+
+        if records {
+            RECORDING_SCOPES.get_or_init(Default::default).lock().unwrap()
+                .push(Arc::downgrade(&self.0));
+        }
+
+        Ok(vcr)
+    }
+
+    /// Finish the session of every middleware in the scope (see
+    /// [VcrMiddleware::finish]) and write their recordings, returning the
+    /// sessions' reports in the order the middlewares were created.
+    ///
+    /// The middlewares are removed from the scope, so finishing it again has
+    /// no effect on them.
+    pub async fn finish(&self) -> Result<Vec<SessionReport>, VcrError> {
+        let middlewares = std::mem::take(
+            &mut self.0.lock().unwrap().middlewares
+        );
+        let mut reports = vec![];
+
+        for scoped in middlewares.iter() {
+            reports.push(scoped.vcr.finish().await);
+        }
+
+        for (path, buffer) in middlewares.iter()
+            .filter_map(|scoped| scoped.recording.as_ref())
+        {
+            let recorded = std::mem::take(&mut *buffer.0.lock().unwrap());
+
+            if ! recorded.is_empty() {
+                append_to_cassette(path, &recorded).await?;
+            }
+        }
+
+        Ok(reports)
+    }
+}
+
+/// Write the interactions recorded in every unfinished [IsolationScope] to
+/// their cassettes, blocking the thread until they're written.
+///
+/// This is meant for teardown code that may run after the async runtime has
+/// shut down, such as a Ctrl-C handler, and is best-effort: the cassettes are
+/// written with `std::fs`, without waiting for other middlewares recording to
+/// them, and failures are logged rather than returned. The scopes are not
+/// finished; interactions recorded later are written when they are.
+pub fn flush_all_blocking() {
+    let scopes = match RECORDING_SCOPES.get() {
+        Some(scopes) => std::mem::take(&mut *scopes.lock().unwrap()),
+        None => return,
+    };
+
+    let scopes = scopes.into_iter()
+        .filter(|scope| match scope.upgrade() {
+            Some(scope) => {
+                scope.lock().unwrap_or_else(PoisonError::into_inner)
+                    .flush_blocking();
+                true
+            },
+            None => false,
+        })
+        .collect::<Vec<_>>();
+
+    RECORDING_SCOPES.get().unwrap().lock().unwrap().extend(scopes);
+}
+
+impl ScopeState {
+    // Write the recordings of the scope's middlewares to their cassettes
+    // without the async runtime.
+    fn flush_blocking(&mut self) {
+        for (path, buffer) in self.middlewares.iter()
+            .filter_map(|scoped| scoped.recording.as_ref())
+        {
+            let recorded = std::mem::take(&mut *buffer.0.lock().unwrap());
+
+            if recorded.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = append_to_cassette_blocking(path, &recorded) {
+                log::warn!(
+                    "Failed to write recordings to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl Drop for ScopeState {
+    fn drop(&mut self) { self.flush_blocking(); }
+}
+
+// Append interactions to a cassette, holding the lock shared by the
+// middlewares recording to it.
+async fn append_to_cassette(path: &Path, recorded: &[u8])
+-> Result<(), VcrError> {
+    // Ignore error; we only initialize once.
+    let _ = CASSETTES.set(RwLock::new(Default::default()));
+
+    CASSETTES.get().unwrap().write().await
+        .entry(path.to_owned())
+        .or_insert_with(|| RwLock::new(None));
+
+    let recorders = CASSETTES.get().unwrap().read().await;
+    let mut lock = recorders[path].write().await;
+
+    // Middlewares in a scope record YAML.
+    if VcrCassette::detect_format(path).ok() == Some(VcrFormat::Json) {
+        let cassette = match path.exists() {
+            true => Some(fs::read_to_string(path).await?),
+            false => None,
+        };
+
+        fs::write(path, with_json_recordings(cassette, recorded)?).await?;
+    } else {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path).await?;
+
+        file.write_all(recorded).await?;
+    }
+
+    // A cassette loaded to replay it is kept in step with its file.
+    if let Some(cassette) = lock.as_mut() {
+        let recorded =
+            VcrCassette::from_yaml(&String::from_utf8_lossy(recorded))?;
+
+        for (req, res) in recorded.requests.into_iter().zip(recorded.responses)
+        {
+            cassette.push(req, res);
+        }
+    }
+
+    Ok(())
+}
+
+// Append interactions to a cassette with std::fs, without locking it.
+fn append_to_cassette_blocking(path: &Path, recorded: &[u8])
+-> Result<(), VcrError> {
+    use std::io::Write;
+
+    if VcrCassette::detect_format(path).ok() == Some(VcrFormat::Json) {
+        let cassette = match path.exists() {
+            true => Some(std::fs::read_to_string(path)?),
+            false => None,
+        };
+
+        std::fs::write(path, with_json_recordings(cassette, recorded)?)?;
+        return Ok(());
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(recorded)?;
+
+    Ok(())
+}
+
+// A JSON cassette with interactions recorded as YAML appended to it.
+fn with_json_recordings(cassette: Option<String>, recorded: &[u8])
+-> Result<String, VcrError> {
+    let mut cassette = match cassette {
+        Some(cassette) => VcrCassette::from_json(&cassette)?,
+        None => VcrCassette::default(),
+    };
+
+    let recorded = VcrCassette::from_yaml(&String::from_utf8_lossy(recorded))?;
+
+    for (req, res) in recorded.requests.into_iter().zip(recorded.responses) {
+        cassette.push(req, res);
+    }
+
+    cassette.to_json()
+}
+
+// An in-memory writer shared by a recording middleware and its scope.
+#[derive(Clone, Debug, Default)]
+struct ScopeBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl async_std::io::Write for ScopeBuffer {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8])
+    -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>)
+    -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>)
+    -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use futures_timer::Delay;
+    use crate::tests::Scratch;
+
+    // Held by tests that check when a scope's recordings are written, since
+    // flush_all_blocking writes those of every scope.
+    static FLUSHING: async_std::sync::Mutex<()> =
+        async_std::sync::Mutex::new(());
+
+    async fn run_scope(name: &'static str, requests: usize, path: &str)
+    -> surf::Result<usize> {
+        let path = path.to_owned();
+
+        isolated(|ctx| async move {
+            let outer = ctx.middleware(
+                VcrMode::Replay,
+                "test-sessions/simple.yml"
+            ).await?;
+            let vcr = ctx.middleware(VcrMode::Record, path).await?;
+
+            let client = surf::Client::new()
+                .with(vcr)
+                .with(outer);
+
+            for i in 0..requests {
+                let mut res = client.get("https://example.com")
+                    .header("X-some-header", "another hello")
+                    .header("X-scope", name)
+                    .await?;
+
+                // Each scope replays the matching interactions in turn.
+                let expected = match i {
+                    0 => "A Response",
+                    _ => "And Another Response",
+                };
+                assert_eq!(res.body_string().await?, expected);
+
+                Delay::new(Duration::from_millis(5)).await;
+            }
+
+            let reports = ctx.finish().await?;
+            assert!(reports.iter().all(|r| r.requests == requests));
+            assert!(reports.iter().all(|r| r.unfinished == 0));
+
+            Ok::<_, surf::Error>(requests)
+        }).await?
+    }
+
+    #[async_std::test]
+    async fn isolate_scopes_sharing_a_cassette() -> surf::Result<()> {
+        let _flushing = FLUSHING.lock().await;
+        let path = "test-sessions/scratch/isolation-test.yml";
+        let _path = Scratch::new(path);
+
+        // The short scope finishes while the long scope is still running.
+        let short = async_std::task::spawn(run_scope("short", 2, path));
+        let long = async_std::task::spawn(run_scope("long", 8, path));
+
+        assert_eq!(short.await?, 2);
+        assert_eq!(long.await?, 8);
+
+        let cassette = VcrCassette::load(path).await?;
+        let scopes = cassette.requests.iter()
+            .map(|req| req.header_value("x-scope").unwrap())
+            .collect::<Vec<_>>();
+
+        // Each scope's interactions were written together.
+        let mut expected = vec!["short".to_owned(); 2];
+        expected.extend(vec!["long".to_owned(); 8]);
+        assert_eq!(scopes, expected);
+
+        Ok(())
+    }
+
+    async fn record_in_scope(ctx: &IsolationScope, mode: VcrMode, path: &str)
+    -> surf::Result<()> {
+        let outer = ctx.middleware(
+            VcrMode::Replay,
+            "test-sessions/simple.yml"
+        ).await?;
+        let vcr = ctx.middleware(mode, path).await?;
+
+        surf::Client::new().with(vcr).with(outer)
+            .get("https://example.com")
+            .header("X-some-header", "another hello")
+            .await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_recordings_when_the_scope_panics() {
+        let path = "test-sessions/scratch/isolation-panic-test.yml";
+        let _path = Scratch::new(path);
+
+        let panicked = std::panic::catch_unwind(|| {
+            async_std::task::block_on(isolated(|ctx| async move {
+                record_in_scope(&ctx, VcrMode::Record, path).await.unwrap();
+                panic!("the test failed");
+            }))
+        });
+        assert!(panicked.is_err());
+
+        let cassette = async_std::task::block_on(VcrCassette::load(path))
+            .unwrap();
+        assert_eq!(cassette.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn replace_a_cassette_once_per_scope() -> surf::Result<()> {
+        let path = "test-sessions/scratch/isolation-all-test.yml";
+        let _path = Scratch::new(path);
+
+        for _ in 0..2 {
+            isolated(|ctx| async move {
+                record_in_scope(&ctx, VcrMode::All, path).await?;
+                record_in_scope(&ctx, VcrMode::All, path).await
+            }).await??;
+
+            // Both middlewares' interactions are kept, and those of the
+            // earlier scope are replaced.
+            assert_eq!(VcrCassette::load(path).await?.len(), 2);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn write_recordings_when_the_scope_is_dropped() -> surf::Result<()> {
+        let _flushing = FLUSHING.lock().await;
+
+        for path in [
+            "test-sessions/scratch/isolation-drop-test.yml",
+            "test-sessions/scratch/isolation-drop-test.json",
+        ] {
+            let _path = Scratch::new(path);
+
+            let ctx = IsolationScope::default();
+            record_in_scope(&ctx, VcrMode::Record, path).await?;
+            assert!(! has_recording(Path::new(path)));
+
+            drop(ctx);
+            assert_eq!(VcrCassette::load(path).await?.len(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn flush_all_scopes_blocking() -> surf::Result<()> {
+        let _flushing = FLUSHING.lock().await;
+
+        let path = "test-sessions/scratch/isolation-flush-test.yml";
+        let _path = Scratch::new(path);
+
+        let ctx = IsolationScope::default();
+        record_in_scope(&ctx, VcrMode::Record, path).await?;
+
+        flush_all_blocking();
+        assert_eq!(VcrCassette::load(path).await?.len(), 1);
+
+        // Only the interactions recorded since are written when the scope is
+        // finished.
+        record_in_scope(&ctx, VcrMode::Record, path).await?;
+        ctx.finish().await?;
+        assert_eq!(VcrCassette::load(path).await?.len(), 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use http_types::{Method, Url};
+use serde_json::Value;
+
+use crate::{preview::fmt_body_preview, without_fragment, VcrRequest};
+
+
+/// The conditional request headers that carry entity tags; see
+/// `VcrMiddleware::match_conditional_headers`.
+pub const CONDITIONAL_HEADERS: &[&str] = &["if-match", "if-none-match"];
+
+/// Request extension asking the middleware to explain how it searched for the
+/// request in Replay mode.
+///
+/// When a request carries this extension, the middleware attaches a
+/// [MatchTrace] to the response as an extension, or to the
+/// [VcrError::NoMatchingRequest](crate::VcrError::NoMatchingRequest) error
+/// if no recorded request matched.
+///
+/// ```
+/// # async fn runtest() -> surf::Result {
+/// use surf_vcr::{MatchTrace, VcrExplain, VcrMiddleware, VcrMode};
+///
+/// let vcr = VcrMiddleware::new(
+///     VcrMode::Replay,
+///     "test-sessions/simple.yml"
+/// ).await?;
+///
+/// let client = surf::Client::new().with(vcr);
+///
+/// let mut req = surf::get("https://example.com")
+///     .header("X-some-header", "another hello")
+///     .build();
+/// req.set_ext(VcrExplain);
+///
+/// let resp = client.send(req).await?;
+/// println!("{}", resp.ext::<MatchTrace>().unwrap());
+/// # Ok(resp) }
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct VcrExplain;
+
+/// Compares request bodies of a particular content type when searching for a
+/// recorded request.
+///
+/// Register a matcher with `VcrMiddleware::with_body_matcher`.
+pub trait BodyMatcher: Send + Sync {
+    /// Return `true` if the requested body is equivalent to the recorded body.
+    fn matches(&self, recorded: &[u8], requested: &[u8]) -> bool;
+}
+
+/// The content types whose bodies [JsonBodyMatcher] compares; see
+/// `VcrMiddleware::with_json_body_matching`.
+pub const JSON_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "application/json-patch+json",
+    "application/merge-patch+json",
+];
+
+/// Matches JSON bodies by their structure rather than their formatting, so
+/// the order of object keys and insignificant whitespace are ignored.
+///
+/// If either body is not valid JSON, the bodies are compared byte for byte.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct JsonBodyMatcher;
+
+impl BodyMatcher for JsonBodyMatcher {
+    fn matches(&self, recorded: &[u8], requested: &[u8]) -> bool {
+        let parse = |body: &[u8]| serde_json::from_slice::<Value>(body).ok();
+
+        match (parse(recorded), parse(requested)) {
+            (Some(rec), Some(req)) => rec == req,
+            _ => recorded == requested,
+        }
+    }
+}
+
+/// Common sets of fields to compare when searching for a recorded request;
+/// see `VcrMiddleware::with_matcher`.
+///
+/// Each strategy is shorthand for a [VcrMatchOptions].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum MatchStrategy {
+    /// Compare the method, URL, headers, and body.
+    Full,
+    /// Compare the method, URL, and body. This is the default.
+    #[default]
+    MethodUrlAndBody,
+    /// Compare only the method and URL.
+    MethodAndUrl,
+}
+
+/// The fields of a request that are compared when searching for a recorded
+/// request; see `VcrMiddleware::with_match_options`.
+///
+/// By default, the method, URL (including its query), and body are compared;
+/// headers are not, since HTTP clients add and change headers between
+/// versions.
+///
+/// ```
+/// use surf_vcr::VcrMatchOptions;
+///
+/// let opts = VcrMatchOptions {
+///     match_headers: true,
+///     header_ignore_list: vec!["User-Agent".to_owned(), "Date".to_owned()],
+///     ..VcrMatchOptions::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct VcrMatchOptions {
+    pub match_method: bool,
+    /// Compare the URL without its query.
+    pub match_url: bool,
+    pub match_query: bool,
+    /// Compare queries by their parameters, in any order, rather than as
+    /// written; parameters are compared after percent-decoding, and repeated
+    /// parameters must be repeated as often. Off by default.
+    pub unordered_query: bool,
+    pub match_headers: bool,
+    pub match_body: bool,
+    /// Headers that are not compared when `match_headers` is set; names are
+    /// case-insensitive.
+    pub header_ignore_list: Vec<String>,
+}
+
+impl Default for VcrMatchOptions {
+    fn default() -> Self { Self::from(MatchStrategy::default()) }
+}
+
+impl From<MatchStrategy> for VcrMatchOptions {
+    fn from(strategy: MatchStrategy) -> Self {
+        Self {
+            match_method: true,
+            match_url: true,
+            match_query: true,
+            unordered_query: false,
+            match_headers: strategy == MatchStrategy::Full,
+            match_body: strategy != MatchStrategy::MethodAndUrl,
+            header_ignore_list: vec![],
+        }
+    }
+}
+
+impl VcrMatchOptions {
+    // Whether two URLs match; fragments must already be removed.
+    pub(crate) fn urls_match(&self, recorded: &Url, requested: &Url) -> bool {
+        let without_query = |url: &Url| {
+            let mut url = url.clone();
+            url.set_query(None);
+            url
+        };
+
+        let url_matches = without_query(recorded) == without_query(requested);
+
+        let query_matches = if self.unordered_query {
+            query_params(recorded) == query_params(requested)
+        } else {
+            recorded.query() == requested.query()
+        };
+
+        (! self.match_url || url_matches)
+            && (! self.match_query || query_matches)
+    }
+
+    // Whether a header is compared; `also_ignored` lists headers the caller
+    // doesn't compare.
+    pub(crate) fn compares_header(&self, name: &str, also_ignored: &[&str])
+    -> bool {
+        self.match_headers
+            && ! also_ignored.iter().any(|h| h.eq_ignore_ascii_case(name))
+            && ! self.header_ignore_list.iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+    }
+
+    pub(crate) fn headers_match(
+        &self,
+        recorded: &VcrRequest,
+        requested: &VcrRequest,
+        also_ignored: &[&str]
+    ) -> bool {
+        ! self.match_headers
+            || self.compared_headers(recorded, also_ignored)
+                == self.compared_headers(requested, also_ignored)
+    }
+
+    fn compared_headers<'a>(
+        &self,
+        req: &'a VcrRequest,
+        also_ignored: &[&str]
+    ) -> HashMap<&'a String, &'a Vec<String>> {
+        req.headers.iter()
+            .filter(|(name, _)| self.compares_header(name, also_ignored))
+            .collect()
+    }
+}
+
+// The URL's decoded query parameters, sorted.
+fn query_params(url: &Url) -> Vec<(String, String)> {
+    let mut params = url.query_pairs().into_owned().collect::<Vec<_>>();
+    params.sort();
+    params
+}
+
+/// A field of a request that is compared when searching for a recorded
+/// request.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MatchField {
+    Method,
+    Url,
+    Header(String),
+    Body,
+}
+
+impl fmt::Display for MatchField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Method => write!(f, "method"),
+            Self::Url => write!(f, "URL"),
+            Self::Header(name) => write!(f, "header {}", name),
+            Self::Body => write!(f, "body"),
+        }
+    }
+}
+
+/// The first field in which a recorded request differs from the request being
+/// replayed.
+///
+/// A value of `None` means the field is not present in that request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldMismatch {
+    pub field: MatchField,
+    pub recorded: Option<String>,
+    pub requested: Option<String>,
+}
+
+impl fmt::Display for FieldMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn value(v: &Option<String>) -> String {
+            match v {
+                Some(v) => format!("{:?}", v),
+                None => "(missing)".to_owned(),
+            }
+        }
+
+        write!(f, "{} differs: recorded {}, requested {}",
+            self.field, value(&self.recorded), value(&self.requested))
+    }
+}
+
+/// The result of comparing the replayed request to a single recorded request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CandidateTrace {
+    /// The position of the recorded request in the cassette.
+    pub index: usize,
+    /// The first field that did not match, or `None` if the recorded request
+    /// matched.
+    pub mismatch: Option<FieldMismatch>,
+    /// Every field that did not match.
+    pub differing: Vec<MatchField>,
+}
+
+/// A detailed account of the middleware's search for a recorded request.
+///
+/// The trace is rendered as human-readable text via its `Display`
+/// implementation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MatchTrace {
+    /// The (method, URL) bucket of recorded requests that was searched, or
+    /// `None` if every recorded request was a candidate.
+    pub bucket: Option<(Method, Url)>,
+    /// Every recorded request that was compared, in the order compared.
+    pub candidates: Vec<CandidateTrace>,
+    /// The position of the recorded request that was replayed, if any.
+    pub matched: Option<usize>,
+    /// The position of a replayed redirect to the request's URL, if the
+    /// request looks like a client following it.
+    pub redirected_from: Option<usize>,
+}
+
+impl MatchTrace {
+    pub(crate) fn new(
+        recorded: &[VcrRequest],
+        request: &VcrRequest,
+        opts: &VcrMatchOptions,
+        body_limit: usize
+    ) -> Self {
+        let candidates = recorded.iter()
+            .enumerate()
+            .map(|(index, rec)| {
+                let differences = differences(rec, request, opts, body_limit);
+
+                CandidateTrace {
+                    index,
+                    differing: differences.iter()
+                        .map(|d| d.field.clone())
+                        .collect(),
+                    mismatch: differences.into_iter().next(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let matched = candidates.iter()
+            .find(|c| c.mismatch.is_none())
+            .map(|c| c.index);
+
+        Self { bucket: None, candidates, matched, redirected_from: None }
+    }
+}
+
+impl fmt::Display for MatchTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.bucket {
+            Some((method, url)) => writeln!(f,
+                "Searched {} recorded requests for {} {}",
+                self.candidates.len(), method, url)?,
+            None => writeln!(f,
+                "Searched all {} recorded requests",
+                self.candidates.len())?,
+        }
+
+        if self.bucket.is_none() && self.candidates.is_empty() {
+            // Usually the cassette file is empty or wasn't the file expected.
+            return write!(f, "No recorded request matched; the cassette has \
+                no recorded requests");
+        }
+
+        for candidate in self.candidates.iter() {
+            match &candidate.mismatch {
+                Some(mismatch) =>
+                    writeln!(f, "  #{}: {}", candidate.index, mismatch)?,
+                None => writeln!(f, "  #{}: matched", candidate.index)?,
+            }
+        }
+
+        match self.matched {
+            Some(index) => write!(f, "Replayed recorded request #{}", index),
+            None => {
+                write!(f, "No recorded request matched")?;
+
+                // The candidate differing in the fewest fields.
+                let closest = self.candidates.iter()
+                    .filter(|c| ! c.differing.is_empty())
+                    .min_by_key(|c| c.differing.len());
+
+                if let Some(closest) = closest {
+                    let fields = closest.differing.iter()
+                        .map(|field| field.to_string())
+                        .collect::<Vec<_>>();
+
+                    write!(f, "; the closest is #{}, which differs in: {}",
+                        closest.index, fields.join(", "))?;
+                }
+
+                match self.redirected_from {
+                    Some(index) => write!(f, "\nThis looks like a redirect \
+                        follow of interaction #{}; either record the redirect \
+                        chain or disable the Redirect middleware during \
+                        replay", index),
+                    None => Ok(()),
+                }
+            },
+        }
+    }
+}
+
+// Every field in which the requests differ, among those the options compare.
+// The fields are compared in a fixed order (method, URL, headers sorted by
+// name, body) so the reported mismatches are stable. Bodies are shown up to
+// `body_limit` bytes.
+pub(crate) fn differences(
+    recorded: &VcrRequest,
+    request: &VcrRequest,
+    opts: &VcrMatchOptions,
+    body_limit: usize
+) -> Vec<FieldMismatch> {
+    let mut differences = vec![];
+
+    if opts.match_method && recorded.method != request.method {
+        differences.push(FieldMismatch {
+            field: MatchField::Method,
+            recorded: Some(recorded.method.to_string()),
+            requested: Some(request.method.to_string()),
+        });
+    }
+
+    if ! opts.urls_match(&without_fragment(&recorded.url), &request.url) {
+        differences.push(FieldMismatch {
+            field: MatchField::Url,
+            recorded: Some(recorded.url.to_string()),
+            requested: Some(request.url.to_string()),
+        });
+    }
+
+    if opts.match_headers {
+        let names = recorded.headers.keys()
+            .chain(request.headers.keys())
+            .filter(|name| opts.compares_header(name, &[]))
+            .collect::<BTreeSet<_>>();
+
+        for name in names {
+            let rec = recorded.headers.get(name);
+            let req = request.headers.get(name);
+
+            if rec != req {
+                differences.push(FieldMismatch {
+                    field: MatchField::Header(name.to_owned()),
+                    recorded: rec.map(|v| v.join(", ")),
+                    requested: req.map(|v| v.join(", ")),
+                });
+            }
+        }
+    }
+
+    if opts.match_body && recorded.body != request.body {
+        differences.push(FieldMismatch {
+            field: MatchField::Body,
+            recorded: Some(fmt_body_preview(&recorded.body, body_limit)),
+            requested: Some(fmt_body_preview(&request.body, body_limit)),
+        });
+    }
+
+    differences
+}
+
@@ -0,0 +1,334 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{self, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use async_std::{fs, io::BufReader, net::TcpListener, prelude::*, task};
+use surf::{http, Response, StatusCode, Url};
+
+use crate::{Body, VcrCassette, VcrError};
+
+
+// The tasks serving open connections, by connection number; `None` once the
+// server has stopped.
+type Connections = Arc<Mutex<Option<HashMap<usize, task::JoinHandle<()>>>>>;
+
+/// A local HTTP server that replies to requests with the responses recorded
+/// in a cassette.
+///
+/// This allows testing code that creates its own HTTP client. Requests are
+/// matched to recorded requests by their method, path, and query only, since
+/// the host and headers of a request to the mock server will differ from
+/// those recorded. If several recorded requests match, they are served in the
+/// order they were recorded, then the first is repeated.
+///
+/// The server is shut down when the `MockServer` is dropped, closing any open
+/// connections.
+///
+/// The mock server requires the `mock-server` feature.
+///
+/// ```
+/// # async fn serve() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::VcrCassette;
+///
+/// let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+/// let server = cassette.as_mock_server()?;
+///
+/// let url = format!("http://{}/", server.addr());
+/// // Point the code under test at `url`.
+/// # Ok(()) }
+/// ```
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    used: Arc<Mutex<BTreeSet<usize>>>,
+    listener: Option<task::JoinHandle<()>>,
+    connections: Connections,
+}
+
+impl MockServer {
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr { self.addr }
+
+    /// The positions of the cassette entries that have been served, in
+    /// ascending order.
+    pub fn used(&self) -> Vec<usize> {
+        self.used.lock().unwrap().iter().copied().collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let mut tasks = self.connections.lock().unwrap().take()
+            .map(|open| open.into_values().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        tasks.extend(self.listener.take());
+
+        // Cancelling a task waits for it to stop, so they're cancelled in the
+        // background.
+        task::spawn(async move {
+            for task in tasks {
+                task.cancel().await;
+            }
+        });
+    }
+}
+
+impl VcrCassette {
+    /// Start a [MockServer] on a free port of the loopback interface, serving
+    /// the cassette's responses.
+    ///
+    /// Bodies stored in separate files are read from the cassette's directory
+    /// as they are sent.
+    pub fn as_mock_server(&self) -> Result<MockServer, VcrError> {
+        let listener = net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let listener = TcpListener::from(listener);
+
+        let cassette = Arc::new(self.clone());
+        let used = Arc::new(Mutex::new(BTreeSet::new()));
+        let connections: Connections = Arc::new(Mutex::new(Some(
+            HashMap::new()
+        )));
+
+        let served = used.clone();
+        let open = connections.clone();
+
+        let listener = task::spawn(async move {
+            let mut incoming = listener.incoming();
+            let mut count = 0;
+
+            while let Some(stream) = incoming.next().await {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+
+                let mut connections = open.lock().unwrap();
+                let running = match connections.as_mut() {
+                    Some(running) => running,
+                    None => break,
+                };
+
+                let cassette = cassette.clone();
+                let used = served.clone();
+                let open = open.clone();
+                let id = count;
+                count += 1;
+
+                // The task removes itself once the client disconnects; the
+                // lock is held until it's added.
+                running.insert(id, task::spawn(async move {
+                    let _ = async_h1::accept(stream, |req| {
+                        let cassette = cassette.clone();
+                        let used = used.clone();
+
+                        async move { Ok(respond(&cassette, &used, &req).await) }
+                    }).await;
+
+                    if let Some(running) = open.lock().unwrap().as_mut() {
+                        running.remove(&id);
+                    }
+                }));
+            }
+        });
+
+        Ok(MockServer {
+            addr,
+            used,
+            listener: Some(listener),
+            connections,
+        })
+    }
+}
+
+async fn respond(
+    cassette: &VcrCassette,
+    used: &Mutex<BTreeSet<usize>>,
+    req: &http::Request
+) -> http::Response {
+    let target = origin_form(req.url());
+
+    let matches = cassette.requests.iter()
+        .enumerate()
+        .filter(|(_, rec)| {
+            rec.method == req.method() && origin_form(&rec.url) == target
+        })
+        .map(|(pos, _)| pos)
+        .collect::<Vec<_>>();
+
+    // Serve each match in turn, then repeat the first.
+    let pos = {
+        let mut used = used.lock().unwrap();
+        let pos = matches.iter()
+            .find(|&&pos| ! used.contains(&pos))
+            .or_else(|| matches.first())
+            .copied();
+
+        if let Some(pos) = pos {
+            used.insert(pos);
+        }
+
+        pos
+    };
+
+    let recorded = match pos {
+        Some(pos) => &cassette.responses[pos],
+        None => {
+            let mut res = http::Response::new(StatusCode::NotFound);
+            res.set_body(format!(
+                "No recorded response for {} {}",
+                req.method(),
+                target
+            ));
+            return res;
+        },
+    };
+
+    let mut res: http::Response = Response::from(recorded).into();
+
+    if let Body::File { file, .. } = &recorded.body {
+        let path = cassette.body_path(file);
+
+        // The body is read from its file as the client reads it.
+        let body = match fs::File::open(&path).await {
+            Ok(file) => {
+                let len = file.metadata().await.ok()
+                    .map(|meta| meta.len() as usize);
+
+                http::Body::from_reader(BufReader::new(file), len)
+            },
+            Err(e) => {
+                let mut res = http::Response::new(
+                    StatusCode::InternalServerError
+                );
+                res.set_body(format!(
+                    "Cannot read the body file {}: {}",
+                    path.display(),
+                    e
+                ));
+                return res;
+            },
+        };
+
+        res.set_body(body);
+    }
+
+    res
+}
+
+// The path and query of a URL.
+fn origin_form(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, time::Duration};
+    use async_std::{io, net::TcpStream};
+    use crate::tests::Scratch;
+
+    #[async_std::test]
+    async fn serve_recorded_responses() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let server = cassette.as_mock_server()?;
+
+        let mut res = surf::get(format!("http://{}/", server.addr())).await
+            .unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "A Response");
+
+        let res = surf::get(format!("http://{}/missing", server.addr())).await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        assert_eq!(server.used(), vec![0]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn serve_repeated_requests_in_order() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let server = cassette.as_mock_server()?;
+        let url = format!("http://{}/", server.addr());
+
+        let mut bodies = vec![];
+
+        for _ in 0..4 {
+            bodies.push(surf::get(&url).recv_string().await.unwrap());
+        }
+
+        assert_eq!(bodies, vec![
+            "A Response", "A Response", "And Another Response", "A Response",
+        ]);
+        assert_eq!(server.used(), vec![0, 1, 2]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn serve_bodies_from_files() -> Result<(), VcrError> {
+        let dir = "test-sessions/scratch/mock-server-bodies";
+        let _dir = Scratch::new(dir);
+
+        let body = "0123456789abcdef".repeat(4096);
+
+        fs::create_dir_all(dir).await?;
+        fs::write(format!("{}/body.txt", dir), &body).await?;
+
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml")
+            .await?;
+        cassette.responses[0].body = Body::File {
+            file: PathBuf::from("scratch/mock-server-bodies/body.txt"),
+            len: None,
+            sha256: None,
+        };
+
+        let server = cassette.as_mock_server()?;
+        let res = surf::get(format!("http://{}/", server.addr()))
+            .recv_string().await.unwrap();
+
+        assert_eq!(res, body);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn close_connections_when_dropped() -> Result<(), VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        let server = cassette.as_mock_server()?;
+
+        let mut stream = TcpStream::connect(server.addr()).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+
+        let mut buf = vec![0; 1024];
+        let n = stream.read(&mut buf).await?;
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+
+        drop(server);
+
+        // The kept-alive connection is closed rather than left open.
+        let mut rest = vec![];
+        let closed = io::timeout(
+            Duration::from_secs(5),
+            stream.read_to_end(&mut rest)
+        ).await;
+
+        if let Err(e) = closed {
+            assert_ne!(e.kind(), io::ErrorKind::TimedOut);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Body;
+
+
+/// The number of bytes of a body that diagnostics show by default; see
+/// [set_diagnostic_body_limit].
+pub const DEFAULT_DIAGNOSTIC_BODY_LIMIT: usize = 512;
+
+static DIAGNOSTIC_BODY_LIMIT: AtomicUsize =
+    AtomicUsize::new(DEFAULT_DIAGNOSTIC_BODY_LIMIT);
+
+/// Set the number of bytes of a body shown in error messages, match traces,
+/// and assertion failures, for the whole process.
+///
+/// Pass `usize::MAX` to show bodies in full. A middleware's limit can be set
+/// separately with `VcrMiddleware::with_diagnostic_body_limit`.
+pub fn set_diagnostic_body_limit(bytes: usize) {
+    DIAGNOSTIC_BODY_LIMIT.store(bytes, Ordering::SeqCst);
+}
+
+pub(crate) fn diagnostic_body_limit() -> usize {
+    DIAGNOSTIC_BODY_LIMIT.load(Ordering::SeqCst)
+}
+
+/// Render a body for human-readable output, showing at most `max_bytes` of
+/// it.
+///
+/// Text is cut at the last character boundary before `max_bytes`; binary
+/// bodies are shown as hex. A truncated body is followed by its total size.
+/// Bodies stored elsewhere are shown by their location.
+pub fn fmt_body_preview(body: &Body, max_bytes: usize) -> String {
+    match body {
+        Body::Str(s) => fmt_text_preview(s, max_bytes),
+        Body::Bytes(b) => {
+            let hex = b.iter()
+                .take(max_bytes)
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>();
+
+            let more = if b.len() > max_bytes { " ..." } else { "" };
+
+            format!("<binary, {} bytes: {}{}>", b.len(), hex.join(" "), more)
+        },
+        Body::File { file, .. } => format!("<{}>", file.display()),
+        Body::Ref { body_ref } => format!("<shared body {}>", body_ref),
+    }
+}
+
+// Render text for human-readable output, showing at most `max_bytes` of it.
+pub(crate) fn fmt_text_preview(s: &str, max_bytes: usize) -> String {
+    if s.len() <= max_bytes {
+        return s.to_owned();
+    }
+
+    let mut end = max_bytes;
+    while ! s.is_char_boundary(end) { end -= 1; }
+
+    format!("{}... ({} bytes in total)", &s[..end], s.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_text_at_char_boundaries() {
+        let body = Body::Str("héllo".to_owned());
+
+        assert_eq!(fmt_body_preview(&body, 6), "héllo");
+        assert_eq!(fmt_body_preview(&body, 5), "héll... (6 bytes in total)");
+        // The second byte is within "é".
+        assert_eq!(fmt_body_preview(&body, 2), "h... (6 bytes in total)");
+        assert_eq!(fmt_body_preview(&body, 0), "... (6 bytes in total)");
+    }
+
+    #[test]
+    fn render_binary_as_hex() {
+        let body = Body::Bytes(vec![0x00, 0xff, 0x10]);
+
+        assert_eq!(
+            fmt_body_preview(&body, 3),
+            "<binary, 3 bytes: 00 ff 10>"
+        );
+        assert_eq!(
+            fmt_body_preview(&body, 2),
+            "<binary, 3 bytes: 00 ff ...>"
+        );
+    }
+
+    #[test]
+    fn override_the_global_limit() {
+        assert_eq!(diagnostic_body_limit(), DEFAULT_DIAGNOSTIC_BODY_LIMIT);
+
+        set_diagnostic_body_limit(usize::MAX);
+        let body = Body::Str("x".repeat(DEFAULT_DIAGNOSTIC_BODY_LIMIT * 2));
+        assert_eq!(
+            fmt_body_preview(&body, diagnostic_body_limit()).len(),
+            DEFAULT_DIAGNOSTIC_BODY_LIMIT * 2
+        );
+
+        set_diagnostic_body_limit(DEFAULT_DIAGNOSTIC_BODY_LIMIT);
+    }
+}
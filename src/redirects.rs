@@ -0,0 +1,138 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::borrow::Cow;
+
+use http_types::{Method, Url};
+
+use crate::{
+    cassette::IndexedCassette,
+    core::Session,
+    without_fragment,
+    VcrRequest,
+    VcrResponse,
+};
+
+
+// The most redirects followed within a single replay.
+const MAX_REDIRECTS: usize = 10;
+
+impl VcrResponse {
+    // The URL a redirect points to, resolved against the URL of its request.
+    pub(crate) fn redirect_target(&self, from: &Url) -> Option<Url> {
+        if ! self.status.is_redirection() {
+            return None;
+        }
+
+        let location = self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("location"))
+            .and_then(|(_, v)| v.first())?;
+
+        from.join(location).ok().map(|url| without_fragment(&url))
+    }
+}
+
+impl Session {
+    // Mark the interaction as replayed and return the response to replay,
+    // with its position. If redirects are inlined, a recorded redirect chain
+    // is followed to its last response, and each hop is marked as replayed.
+    pub(crate) fn take_final_response<'a>(
+        &self,
+        cassette: &'a IndexedCassette,
+        request: &VcrRequest,
+        pos: usize
+    ) -> (usize, Cow<'a, VcrResponse>) {
+        let mut pos = pos;
+        let mut response = self.take_response(cassette, request, pos);
+
+        if self.inline_redirects {
+            for _ in 0..MAX_REDIRECTS {
+                let next = response
+                    .redirect_target(&cassette.requests[pos].url)
+                    .and_then(|target| self.find_hop(cassette, pos, &target));
+
+                match next {
+                    Some(next) => {
+                        pos = next;
+                        response = self.take_response(
+                            cassette,
+                            &cassette.requests[next],
+                            next
+                        );
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        if let Some(target) =
+            response.redirect_target(&cassette.requests[pos].url)
+        {
+            self.state.0.redirects.lock().unwrap().insert(target, pos);
+        }
+
+        (pos, response)
+    }
+
+    // The position of the replayed redirect that points to `url`, if any.
+    pub(crate) fn redirected_from(&self, url: &Url) -> Option<usize> {
+        self.state.0.redirects.lock().unwrap()
+            .get(&without_fragment(url))
+            .copied()
+    }
+
+    // Find the recorded request following the redirect at `pos`: the first
+    // later request for the target, preferring one not yet replayed. The
+    // request is a GET unless the redirect kept the method.
+    fn find_hop(&self, cassette: &IndexedCassette, pos: usize, target: &Url)
+    -> Option<usize> {
+        let method = cassette.requests[pos].method;
+        let played = self.state.0.played.lock().unwrap();
+
+        let hops = (pos + 1..cassette.len())
+            .filter(|&i| {
+                let req = &cassette.requests[i];
+                req.url == *target
+                    && (req.method == Method::Get || req.method == method)
+            })
+            .collect::<Vec<_>>();
+
+        hops.iter()
+            .find(|&&i| ! played.contains(&i))
+            .or_else(|| hops.first())
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use surf::StatusCode;
+
+    #[test]
+    fn resolve_redirect_targets() {
+        let from = Url::parse("https://example.com/a/b?x=1").unwrap();
+
+        let found = VcrResponse::new(StatusCode::Found)
+            .with_header("Location", "/c#top");
+        assert_eq!(
+            found.redirect_target(&from),
+            Some(Url::parse("https://example.com/c").unwrap())
+        );
+
+        let moved = VcrResponse::new(StatusCode::MovedPermanently)
+            .with_header("Location", "https://example.org/");
+        assert_eq!(
+            moved.redirect_target(&from),
+            Some(Url::parse("https://example.org/").unwrap())
+        );
+
+        let ok = VcrResponse::new(StatusCode::Ok)
+            .with_header("Location", "/c");
+        assert_eq!(ok.redirect_target(&from), None);
+
+        let not_modified = VcrResponse::new(StatusCode::NotModified);
+        assert_eq!(not_modified.redirect_target(&from), None);
+    }
+}
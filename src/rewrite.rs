@@ -0,0 +1,414 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use http_types::Url;
+
+use crate::{VcrCassette, REDACTED};
+
+
+impl VcrCassette {
+    /// Create a copy of the cassette with the query parameters of every
+    /// request URL sorted by name.
+    ///
+    /// Parameters with the same name keep their relative order, and their
+    /// encoding is not changed.
+    pub fn normalize_query_params(&self) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            sort_query(&mut req.url);
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with the host of every request URL that
+    /// is `from` replaced with `to`, such as to move a cassette between
+    /// environments.
+    ///
+    /// Nothing else in the requests is changed, including `Host` headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `to` is not a valid host.
+    ///
+    /// ```
+    /// # async fn rewrite() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?
+    ///     .rewrite_host("example.com", "api.example.com");
+    /// # Ok(()) }
+    /// ```
+    pub fn rewrite_host(&self, from: &str, to: &str) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            if req.url.host_str() == Some(from) {
+                req.url.set_host(Some(to))
+                    .unwrap_or_else(|e| panic!("Invalid host {}: {}", to, e));
+            }
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with the scheme of every request URL
+    /// that is `from` replaced with `to`, such as to move a cassette from HTTP
+    /// to HTTPS.
+    ///
+    /// Ports that are the default for `from` become the default for `to`;
+    /// other ports are kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheme cannot be changed, such as from `https` to
+    /// `file`.
+    pub fn rewrite_scheme(&self, from: &str, to: &str) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            if req.url.scheme() == from {
+                req.url.set_scheme(to).unwrap_or_else(|_| {
+                    panic!("Cannot change the scheme of {} to {}", req.url, to)
+                });
+            }
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with the query parameter `name` set to
+    /// `value` in every request URL, such as to add a version parameter that
+    /// earlier recordings lacked.
+    ///
+    /// A parameter that is already present is replaced in place; otherwise it
+    /// is appended. The name and value are percent-encoded as needed.
+    ///
+    /// ```
+    /// # async fn rewrite() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?
+    ///     .add_query_param("api_version", "2024-01-01");
+    /// # Ok(()) }
+    /// ```
+    pub fn add_query_param(&self, name: &str, value: &str) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            set_query_param(&mut req.url, name, value);
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with the query parameter `name` removed
+    /// from every request URL.
+    pub fn remove_query_param(&self, name: &str) -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            remove_query_param(&mut req.url, name);
+        }
+
+        cassette
+    }
+
+    /// Create a copy of the cassette with the value of the query parameter
+    /// `name` replaced with `new` wherever it is `old`, such as to migrate
+    /// cassettes when the format of an API key changes.
+    ///
+    /// Values are compared after percent-decoding. Other parameters, and
+    /// occurrences of `name` with other values, are not changed.
+    ///
+    /// ```
+    /// # async fn rewrite() -> Result<(), surf_vcr::VcrError> {
+    /// use surf_vcr::VcrCassette;
+    ///
+    /// let cassette = VcrCassette::load("test-sessions/simple.yml").await?
+    ///     .replace_query_param_value("api_key", "abc123", "key_abc123");
+    /// # Ok(()) }
+    /// ```
+    pub fn replace_query_param_value(&self, name: &str, old: &str, new: &str)
+    -> VcrCassette {
+        let mut cassette = self.clone();
+
+        for (req, _) in cassette.entries_mut() {
+            replace_query_param_value(&mut req.url, name, old, new);
+        }
+
+        cassette
+    }
+}
+
+// Replace the values of the named query parameters.
+pub(crate) fn redact_query(url: &Url, names: &[String]) -> Url {
+    let mut url = url.clone();
+
+    let query = match url.query() {
+        Some(query) if ! names.is_empty() => query.to_owned(),
+        _ => return url,
+    };
+
+    let params = query.split('&')
+        .map(|param| {
+            let name = param_name(param);
+
+            if names.iter().any(|n| n == name) {
+                format!("{}={}", name, REDACTED)
+            } else {
+                param.to_owned()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    url.set_query(Some(&params.join("&")));
+    url
+}
+
+fn sort_query(url: &mut Url) {
+    let query = match url.query() {
+        Some(query) => query.to_owned(),
+        None => return,
+    };
+
+    let mut params = query.split('&').collect::<Vec<_>>();
+    params.sort_by(|a, b| param_name(a).cmp(param_name(b)));
+
+    url.set_query(Some(&params.join("&")));
+}
+
+// Set a query parameter, replacing every occurrence of it with one at the
+// position of the first. The encoding of other parameters is not changed.
+fn set_query_param(url: &mut Url, name: &str, value: &str) {
+    let param = encode_param(name, value);
+    let name = param_name(&param);
+
+    let mut found = false;
+
+    let mut params = url.query().unwrap_or_default()
+        .split('&')
+        .filter(|p| ! p.is_empty())
+        .filter_map(|p| {
+            if param_name(p) != name {
+                Some(p)
+            } else if found {
+                None
+            } else {
+                found = true;
+                Some(param.as_str())
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if ! found {
+        params.push(&param);
+    }
+
+    let query = params.join("&");
+    url.set_query(Some(&query));
+}
+
+fn remove_query_param(url: &mut Url, name: &str) {
+    let query = match url.query() {
+        Some(query) => query.to_owned(),
+        None => return,
+    };
+
+    let encoded = encode_param(name, "");
+    let name = param_name(&encoded);
+
+    let params = query.split('&')
+        .filter(|p| ! p.is_empty() && param_name(p) != name)
+        .collect::<Vec<_>>();
+
+    if params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.set_query(Some(&params.join("&")));
+    }
+}
+
+fn replace_query_param_value(url: &mut Url, name: &str, old: &str, new: &str) {
+    let query = match url.query() {
+        Some(query) => query.to_owned(),
+        None => return,
+    };
+
+    let replacement = encode_param(name, new);
+
+    let params = query.split('&')
+        .map(|p| match decode_param(p) {
+            Some((n, v)) if n == name && v == old => replacement.as_str(),
+            _ => p,
+        })
+        .collect::<Vec<_>>();
+
+    url.set_query(Some(&params.join("&")));
+}
+
+// A query parameter, percent-encoded as a form would encode it.
+fn encode_param(name: &str, value: &str) -> String {
+    let mut url = Url::parse("http://localhost/").unwrap();
+    url.query_pairs_mut().append_pair(name, value);
+
+    url.query().unwrap_or_default().to_owned()
+}
+
+// The decoded name and value of a query parameter.
+fn decode_param(param: &str) -> Option<(String, String)> {
+    let mut url = Url::parse("http://localhost/").unwrap();
+    url.set_query(Some(param));
+
+    let (name, value) = url.query_pairs().next()?;
+    Some((name.into_owned(), value.into_owned()))
+}
+
+fn param_name(param: &str) -> &str {
+    param.split('=').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_query_params() {
+        let mut url = Url::parse(
+            "https://example.com/search?q=rust%20vcr&page=2&filter=b&filter=a"
+        ).unwrap();
+        sort_query(&mut url);
+
+        assert_eq!(
+            url.as_str(),
+            "https://example.com/search?filter=b&filter=a&page=2&q=rust%20vcr"
+        );
+
+        let mut url = Url::parse("https://example.com/search").unwrap();
+        sort_query(&mut url);
+
+        assert_eq!(url.as_str(), "https://example.com/search");
+    }
+
+    #[test]
+    fn redact_query_values() {
+        let url = Url::parse(
+            "https://example.com/data?api_key=s3cr3t&page=2&api_key=other"
+        ).unwrap();
+
+        assert_eq!(
+            redact_query(&url, &["api_key".to_owned()]).as_str(),
+            "https://example.com/data\
+                ?api_key=REDACTED&page=2&api_key=REDACTED"
+        );
+        assert_eq!(redact_query(&url, &[]), url);
+    }
+
+    #[test]
+    fn set_and_remove_query_params() {
+        let mut url = Url::parse(
+            "https://example.com/data?v=1&q=rust%20vcr&v=2"
+        ).unwrap();
+
+        set_query_param(&mut url, "v", "2024-01-01");
+        assert_eq!(url.query(), Some("v=2024-01-01&q=rust%20vcr"));
+
+        set_query_param(&mut url, "name", "a b&c");
+        assert_eq!(url.query(), Some("v=2024-01-01&q=rust%20vcr&name=a+b%26c"));
+
+        remove_query_param(&mut url, "q");
+        assert_eq!(url.query(), Some("v=2024-01-01&name=a+b%26c"));
+
+        remove_query_param(&mut url, "v");
+        remove_query_param(&mut url, "name");
+        assert_eq!(url.as_str(), "https://example.com/data");
+
+        set_query_param(&mut url, "v", "1");
+        assert_eq!(url.as_str(), "https://example.com/data?v=1");
+    }
+
+    #[test]
+    fn replace_query_param_values() {
+        let mut url = Url::parse(
+            "https://example.com/data?key=a%20b&page=2&key=c&other=a+b"
+        ).unwrap();
+
+        replace_query_param_value(&mut url, "key", "a b", "new key");
+        assert_eq!(url.query(), Some("key=new+key&page=2&key=c&other=a+b"));
+
+        replace_query_param_value(&mut url, "key", "missing", "x");
+        assert_eq!(url.query(), Some("key=new+key&page=2&key=c&other=a+b"));
+
+        let mut url = Url::parse("https://example.com/data").unwrap();
+        replace_query_param_value(&mut url, "key", "a", "b");
+        assert_eq!(url.as_str(), "https://example.com/data");
+    }
+
+    #[async_std::test]
+    async fn add_cassette_query_param() -> Result<(), crate::VcrError> {
+        let cassette = VcrCassette::load("test-sessions/simple.yml").await?
+            .add_query_param("api_version", "2024-01-01");
+
+        assert!(cassette.requests.iter()
+            .all(|r| r.url.query() == Some("api_version=2024-01-01")));
+
+        let removed = cassette.remove_query_param("api_version");
+        assert!(removed.requests.iter().all(|r| r.url.query().is_none()));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn normalize_cassette_query_params() -> Result<(), crate::VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+
+        for (req, _) in cassette.entries_mut() {
+            req.url.set_query(Some("b=2&a=1"));
+        }
+
+        let normalized = cassette.normalize_query_params();
+
+        assert!(
+            normalized.requests.iter().all(|r| r.url.query() == Some("a=1&b=2"))
+        );
+        assert_eq!(cassette.requests[0].url.query(), Some("b=2&a=1"));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rewrite_cassette_host() -> Result<(), crate::VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        cassette.requests[2].url = Url::parse("https://other.com/a?b=c")
+            .unwrap();
+
+        let rewritten = cassette.rewrite_host("example.com", "example.org");
+
+        assert_eq!(rewritten.requests[0].url.as_str(), "https://example.org/");
+        assert_eq!(rewritten.requests[2].url, cassette.requests[2].url);
+        assert_eq!(rewritten.requests[1].headers, cassette.requests[1].headers);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rewrite_cassette_scheme() -> Result<(), crate::VcrError> {
+        let mut cassette = VcrCassette::load("test-sessions/simple.yml").await?;
+        cassette.requests[0].url = Url::parse("http://example.com/a").unwrap();
+        cassette.requests[1].url = Url::parse("http://example.com:8080/")
+            .unwrap();
+
+        let rewritten = cassette.rewrite_scheme("http", "https");
+
+        assert_eq!(rewritten.requests[0].url.as_str(), "https://example.com/a");
+        assert_eq!(
+            rewritten.requests[1].url.as_str(),
+            "https://example.com:8080/"
+        );
+        assert_eq!(rewritten.requests[2].url, cassette.requests[2].url);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde::{Serialize, Deserialize};
+
+use crate::VcrCassette;
+
+
+/// When an interaction was recorded.
+///
+/// Times are in milliseconds since the start of the recording session, as
+/// measured by a monotonic clock, so they're unaffected by changes to the
+/// system clock; times from different sessions can't be compared. Timing is
+/// never considered when matching requests.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct InteractionTiming {
+    /// The order in which the interaction was written to the cassette in its
+    /// session, starting from zero.
+    pub sequence: u64,
+    /// When the request was sent.
+    pub started_at: u64,
+    /// When the response, including its body, was received.
+    pub finished_at: u64,
+}
+
+/// An interaction on a cassette's timeline; see [VcrCassette::timeline].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TimelineEntry {
+    /// The position of the interaction in the cassette.
+    pub index: usize,
+    pub sequence: u64,
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// The positions of the interactions that were in progress at the same
+    /// time as this one, in the order they started.
+    pub overlaps: Vec<usize>,
+}
+
+/// Statistics describing a cassette; see [VcrCassette::stats].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CassetteStats {
+    /// The number of request/response pairs.
+    pub interactions: usize,
+    /// The number of interactions with recorded timing.
+    pub timed_interactions: usize,
+    /// The greatest number of requests that were in progress at once.
+    pub max_concurrent_requests: usize,
+}
+
+impl VcrCassette {
+    /// List the interactions with recorded timing (see [InteractionTiming]) in
+    /// the order they started, with the interactions each overlapped.
+    pub fn timeline(&self) -> Vec<TimelineEntry> {
+        let mut timed = self.responses.iter()
+            .enumerate()
+            .filter_map(|(i, res)| res.timing.as_ref().map(|t| (i, t)))
+            .collect::<Vec<_>>();
+
+        timed.sort_by_key(|(_, t)| (t.started_at, t.sequence));
+
+        timed.iter()
+            .map(|(index, timing)| TimelineEntry {
+                index: *index,
+                sequence: timing.sequence,
+                started_at: timing.started_at,
+                finished_at: timing.finished_at,
+                overlaps: timed.iter()
+                    .filter(|(i, other)| i != index && overlap(timing, other))
+                    .map(|(i, _)| *i)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Calculate statistics describing the cassette.
+    pub fn stats(&self) -> CassetteStats {
+        let timeline = self.timeline();
+
+        // The most requests in progress at once is the most in progress when
+        // one of them started.
+        let max_concurrent_requests = timeline.iter()
+            .map(|entry| {
+                1 + timeline.iter()
+                    .filter(|other| other.index != entry.index
+                        && other.started_at <= entry.started_at
+                        && entry.started_at < other.finished_at)
+                    .count()
+            })
+            .max()
+            .unwrap_or_default();
+
+        CassetteStats {
+            interactions: self.len(),
+            timed_interactions: timeline.len(),
+            max_concurrent_requests,
+        }
+    }
+}
+
+// Whether two interactions were in progress at the same time; an interaction
+// that starts as another finishes does not overlap it.
+fn overlap(a: &InteractionTiming, b: &InteractionTiming) -> bool {
+    a.started_at < b.finished_at && b.started_at < a.finished_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use surf::{http::Method, StatusCode, Url};
+    use crate::{Body, VcrRequest, VcrResponse};
+
+    fn timed_cassette(timings: &[Option<(u64, u64)>]) -> VcrCassette {
+        VcrCassette::build_from_pairs(timings.iter().enumerate()
+            .map(|(i, timing)| (
+                VcrRequest::new(
+                    Method::Get,
+                    Url::parse("https://example.com").unwrap()
+                ),
+                VcrResponse {
+                    status: StatusCode::Ok,
+                    headers: HashMap::new(),
+                    body: Body::Str("".to_owned()),
+                    timing: timing.map(|(started_at, finished_at)| {
+                        InteractionTiming {
+                            sequence: i as u64,
+                            started_at,
+                            finished_at,
+                        }
+                    }),
+                    ..Default::default()
+                }
+            )))
+    }
+
+    #[test]
+    fn detect_overlapping_interactions() {
+        let cassette = timed_cassette(&[
+            Some((10, 50)),
+            Some((0, 20)),
+            None,
+            Some((50, 60)),
+            Some((15, 18)),
+        ]);
+
+        let timeline = cassette.timeline()
+            .into_iter()
+            .map(|entry| (entry.index, entry.overlaps))
+            .collect::<Vec<_>>();
+
+        assert_eq!(timeline, vec![
+            (1, vec![0, 4]),
+            (0, vec![1, 4]),
+            (4, vec![1, 0]),
+            (3, vec![]),
+        ]);
+    }
+
+    #[test]
+    fn count_concurrent_requests() {
+        let cassette = timed_cassette(&[
+            Some((10, 50)),
+            Some((0, 20)),
+            None,
+            Some((50, 60)),
+            Some((15, 18)),
+        ]);
+
+        assert_eq!(cassette.stats(), CassetteStats {
+            interactions: 5,
+            timed_interactions: 4,
+            max_concurrent_requests: 3,
+        });
+
+        assert_eq!(
+            timed_cassette(&[None]).stats().max_concurrent_requests,
+            0
+        );
+    }
+
+    #[test]
+    fn serialize_timing() {
+        let cassette = timed_cassette(&[Some((3, 7)), None]);
+        let yaml = cassette.to_yaml().unwrap();
+
+        assert_eq!(yaml.matches("timing:").count(), 1);
+        assert_eq!(VcrCassette::from_yaml(&yaml).unwrap(), cassette);
+    }
+}
@@ -0,0 +1,217 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use roxmltree::{Document, Node};
+
+use crate::BodyMatcher;
+#[cfg(feature = "surf")]
+use crate::VcrMiddleware;
+
+
+/// The content types whose bodies [XmlBodyMatcher] compares; see
+/// [VcrMiddleware::with_xml_body_matching].
+pub const XML_CONTENT_TYPES: &[&str] = &[
+    "application/xml",
+    "text/xml",
+    "application/soap+xml",
+];
+
+/// Matches XML bodies by their content rather than their formatting.
+///
+/// Both bodies are parsed and compared in a canonical form: attributes are
+/// sorted, names are compared by namespace URI rather than prefix, whitespace
+/// between elements is ignored, and comments are dropped. Text content is
+/// compared as-is. If either body is not well-formed XML, the bodies are
+/// compared byte for byte.
+///
+/// Namespace prefixes are not significant: `<soap:Body>` and `<env:Body>`
+/// match if both prefixes are bound to the same URI, and so do an element in
+/// a default namespace and a prefixed element in the same namespace. The
+/// namespace declarations themselves are not compared, so a body may declare
+/// namespaces it doesn't use. Unprefixed attributes have no namespace, even
+/// within an element in a default namespace.
+///
+/// This matcher requires the `xml` feature.
+///
+/// ```
+/// # async fn create() -> Result<(), surf_vcr::VcrError> {
+/// use surf_vcr::{VcrMiddleware, VcrMode, XmlBodyMatcher};
+///
+/// let vcr = VcrMiddleware::new(
+///     VcrMode::Replay,
+///     "test-sessions/simple.yml"
+/// ).await?
+///     .with_body_matcher("application/soap+xml", XmlBodyMatcher)
+///     .with_body_matcher("text/xml", XmlBodyMatcher);
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct XmlBodyMatcher;
+
+impl BodyMatcher for XmlBodyMatcher {
+    fn matches(&self, recorded: &[u8], requested: &[u8]) -> bool {
+        match (canonicalize(recorded), canonicalize(requested)) {
+            (Some(rec), Some(req)) => rec == req,
+            _ => recorded == requested,
+        }
+    }
+}
+
+#[cfg(feature = "surf")]
+impl VcrMiddleware {
+    /// Compare XML request bodies by their content when searching for a
+    /// recorded request, ignoring formatting and the order of attributes.
+    ///
+    /// This registers [XmlBodyMatcher] for each of the [XML_CONTENT_TYPES];
+    /// register it with [with_body_matcher](VcrMiddleware::with_body_matcher)
+    /// for other XML-based types.
+    pub fn with_xml_body_matching(self) -> Self {
+        XML_CONTENT_TYPES.iter()
+            .fold(self, |vcr, content_type| {
+                vcr.with_body_matcher(*content_type, XmlBodyMatcher)
+            })
+    }
+}
+
+fn canonicalize(xml: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(xml).ok()?;
+    let doc = Document::parse(text).ok()?;
+
+    let mut canonical = String::new();
+    write_node(doc.root_element(), &mut canonical);
+
+    Some(canonical)
+}
+
+// Text is written in quotes and escaped, so it can't be confused with markup.
+fn write_node(node: Node, out: &mut String) {
+    if node.is_element() {
+        let name = node.tag_name();
+
+        let mut attrs = node.attributes().iter()
+            .map(|a| format!(
+                " {{{}}}{}={:?}",
+                a.namespace().unwrap_or_default(), a.name(), a.value()
+            ))
+            .collect::<Vec<_>>();
+        attrs.sort();
+
+        out.push_str(&format!(
+            "<{{{}}}{}",
+            name.namespace().unwrap_or_default(), name.name()
+        ));
+        out.extend(attrs);
+        out.push('>');
+
+        for child in node.children() {
+            write_node(child, out);
+        }
+
+        out.push_str("</>");
+    } else if node.is_text() {
+        let text = node.text().unwrap_or_default();
+
+        if ! text.trim().is_empty() {
+            out.push_str(&format!("{:?}", text));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENVELOPE: &str = r#"<?xml version="1.0"?>
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope"
+    xmlns:m="https://example.com/stock">
+  <soap:Body>
+    <m:GetStockPrice currency="USD" exchange="NYSE">
+      <m:StockName>ACME Corp</m:StockName>
+    </m:GetStockPrice>
+  </soap:Body>
+</soap:Envelope>
+"#;
+
+    #[test]
+    fn equivalent_xml_bodies_match() {
+        let reformatted = concat!(
+            r#"<env:Envelope "#,
+            r#"xmlns:env="http://www.w3.org/2003/05/soap-envelope">"#,
+            r#"<env:Body><!-- the request -->"#,
+            r#"<GetStockPrice xmlns="https://example.com/stock" "#,
+            r#"exchange="NYSE" currency="USD">"#,
+            r#"<StockName>ACME Corp</StockName>"#,
+            r#"</GetStockPrice></env:Body></env:Envelope>"#,
+        );
+
+        assert!(XmlBodyMatcher.matches(
+            ENVELOPE.as_bytes(),
+            reformatted.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn different_xml_bodies_do_not_match() {
+        let different = ENVELOPE.replace("ACME Corp", "ACME  Corp");
+        assert!(! XmlBodyMatcher.matches(
+            ENVELOPE.as_bytes(),
+            different.as_bytes()
+        ));
+
+        let different = ENVELOPE.replace("USD", "EUR");
+        assert!(! XmlBodyMatcher.matches(
+            ENVELOPE.as_bytes(),
+            different.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn match_xml_requests_by_content_type() {
+        use std::collections::HashMap;
+        use surf::{http::Method, Url};
+        use crate::{Body, VcrCassette, VcrRequest};
+
+        let vcr = VcrMiddleware::from_memory(
+            VcrCassette::default().into_memory()
+        ).with_xml_body_matching();
+
+        let request = |content_type: &str, body: &str| VcrRequest {
+            method: Method::Post,
+            url: Url::parse("https://example.com/stock").unwrap(),
+            headers: vec![(
+                "content-type".to_owned(),
+                vec![content_type.to_owned()]
+            )].into_iter().collect::<HashMap<_, _>>(),
+            body: Body::Str(body.to_owned()),
+        };
+
+        let recorded = r#"<m:Price xmlns:m="https://example.com/stock"
+            currency="USD" exchange="NYSE"> 12 </m:Price>"#;
+        let reordered = r#"<Price xmlns="https://example.com/stock"
+            exchange="NYSE" currency="USD"> 12 </Price>"#;
+
+        for content_type in XML_CONTENT_TYPES.iter() {
+            let content_type = format!("{}; charset=utf-8", content_type);
+
+            assert!(vcr.session.request_matches(
+                &request(&content_type, recorded),
+                &request(&content_type, reordered)
+            ));
+        }
+
+        // Bodies of other types are compared byte for byte.
+        assert!(! vcr.session.request_matches(
+            &request("text/plain", recorded),
+            &request("text/plain", reordered)
+        ));
+    }
+
+    #[test]
+    fn malformed_xml_is_compared_as_bytes() {
+        let malformed = b"<soap:Envelope><soap:Body>";
+
+        assert!(XmlBodyMatcher.matches(malformed, malformed));
+        assert!(! XmlBodyMatcher.matches(malformed, ENVELOPE.as_bytes()));
+    }
+}